@@ -0,0 +1,315 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Just enough bencode to read and write extension payloads.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use thiserror::Error;
+
+/// How deeply lists and dictionaries may nest before we refuse to decode them.
+const MAX_DEPTH: usize = 64;
+
+/// A bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BencodeValue>),
+    /// A dictionary, whose keys are always encoded in sorted order.
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BencodeError {
+    #[error("input ended in the middle of a value")]
+    UnexpectedEof,
+    #[error("unexpected byte {byte:#04x} at offset {offset}")]
+    UnexpectedByte { byte: u8, offset: usize },
+    #[error("integer at offset {0} is not canonical")]
+    InvalidInteger(usize),
+    #[error("string length at offset {0} is not canonical")]
+    InvalidLength(usize),
+    #[error("dictionary key at offset {0} is out of order or repeated")]
+    UnsortedKeys(usize),
+    #[error("values are nested more than {} deep", MAX_DEPTH)]
+    TooDeep,
+    #[error("{0} bytes of trailing data after the value")]
+    TrailingData(usize),
+}
+
+/// Bencode a value.
+pub fn encode(value: &BencodeValue) -> Bytes {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    Bytes::from(out)
+}
+
+fn encode_into(value: &BencodeValue, out: &mut Vec<u8>) {
+    match value {
+        BencodeValue::Integer(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+        BencodeValue::Bytes(b) => encode_bytes(b, out),
+        BencodeValue::List(items) => {
+            out.push(b'l');
+            for item in items {
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+        BencodeValue::Dict(entries) => {
+            out.push(b'd');
+            for (key, value) in entries {
+                encode_bytes(key, out);
+                encode_into(value, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(b: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("{}:", b.len()).as_bytes());
+    out.extend_from_slice(b);
+}
+
+/// Decode a buffer holding exactly one canonically-encoded value.
+pub fn decode(buf: &[u8]) -> Result<BencodeValue, BencodeError> {
+    let (value, len) = decode_prefix(buf)?;
+    match buf.len() - len {
+        0 => Ok(value),
+        trailing => Err(BencodeError::TrailingData(trailing)),
+    }
+}
+
+/// Decode the value at the start of `buf`, returning it along with how many bytes it took up.
+pub(crate) fn decode_prefix(buf: &[u8]) -> Result<(BencodeValue, usize), BencodeError> {
+    let mut decoder = Decoder { buf, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or(BencodeError::UnexpectedEof)
+    }
+
+    /// Take the bytes up to (but not including) `delim`, and skip over `delim`.
+    fn take_until(&mut self, delim: u8) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        let len = self.buf[start..]
+            .iter()
+            .position(|b| *b == delim)
+            .ok_or(BencodeError::UnexpectedEof)?;
+        self.pos += len + 1;
+        Ok(&self.buf[start..start + len])
+    }
+
+    fn value(&mut self, depth: usize) -> Result<BencodeValue, BencodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                self.integer().map(BencodeValue::Integer)
+            }
+            b'0'..=b'9' => self.bytes().map(|b| BencodeValue::Bytes(b.to_vec())),
+            b'l' => {
+                let depth = self.descend(depth)?;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.value(depth)?);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::List(items))
+            }
+            b'd' => {
+                let depth = self.descend(depth)?;
+                let mut entries = BTreeMap::new();
+                let mut last_key: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let offset = self.pos;
+                    let key = self.bytes()?;
+                    if last_key.is_some_and(|last| last >= key) {
+                        return Err(BencodeError::UnsortedKeys(offset));
+                    }
+                    last_key = Some(key);
+                    entries.insert(key.to_vec(), self.value(depth)?);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::Dict(entries))
+            }
+            byte => Err(BencodeError::UnexpectedByte {
+                byte,
+                offset: self.pos,
+            }),
+        }
+    }
+
+    fn descend(&mut self, depth: usize) -> Result<usize, BencodeError> {
+        if depth >= MAX_DEPTH {
+            return Err(BencodeError::TooDeep);
+        }
+        self.pos += 1;
+        Ok(depth + 1)
+    }
+
+    fn integer(&mut self) -> Result<i64, BencodeError> {
+        let offset = self.pos;
+        let digits = self.take_until(b'e')?;
+        let canonical = match digits {
+            [] | [b'-'] | [b'-', b'0', ..] => false,
+            [b'0', _, ..] => false,
+            [b'-', rest @ ..] | rest => rest.iter().all(u8::is_ascii_digit),
+        };
+        if !canonical {
+            return Err(BencodeError::InvalidInteger(offset));
+        }
+        core::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(BencodeError::InvalidInteger(offset))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        let offset = self.pos;
+        let digits = self.take_until(b':')?;
+        if digits.is_empty()
+            || (digits.len() > 1 && digits[0] == b'0')
+            || !digits.iter().all(u8::is_ascii_digit)
+        {
+            return Err(BencodeError::InvalidLength(offset));
+        }
+        let len: usize = core::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(BencodeError::InvalidLength(offset))?;
+        if self.buf.len() - self.pos < len {
+            return Err(BencodeError::UnexpectedEof);
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.buf[start..self.pos])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bencode::decode;
+    use crate::bencode::encode;
+    use crate::bencode::BencodeError;
+    use crate::bencode::BencodeValue;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn encode_integers() {
+        assert_eq!(&encode(&BencodeValue::Integer(42))[..], b"i42e");
+        assert_eq!(&encode(&BencodeValue::Integer(-7))[..], b"i-7e");
+        assert_eq!(&encode(&BencodeValue::Integer(0))[..], b"i0e");
+    }
+
+    #[test]
+    fn encode_dict_sorts_keys() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"zebra".to_vec(), BencodeValue::Integer(1));
+        dict.insert(b"apple".to_vec(), BencodeValue::Bytes(b"pie".to_vec()));
+
+        assert_eq!(
+            &encode(&BencodeValue::Dict(dict))[..],
+            b"d5:apple3:pie5:zebrai1ee"
+        );
+    }
+
+    #[test]
+    fn round_trip_nested() {
+        let mut dict = BTreeMap::new();
+        dict.insert(
+            b"list".to_vec(),
+            BencodeValue::List(vec![
+                BencodeValue::Integer(1),
+                BencodeValue::Bytes(Vec::new()),
+                BencodeValue::Dict(BTreeMap::new()),
+            ]),
+        );
+        let value = BencodeValue::Dict(dict);
+
+        assert_eq!(decode(&encode(&value)), Ok(value));
+    }
+
+    #[test]
+    fn decode_rejects_trailing_data() {
+        assert_eq!(decode(b"i1exyz"), Err(BencodeError::TrailingData(3)));
+    }
+
+    #[test]
+    fn decode_rejects_non_canonical_integers() {
+        assert_eq!(decode(b"i03e"), Err(BencodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"i-0e"), Err(BencodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"ie"), Err(BencodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"i+1e"), Err(BencodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"i-e"), Err(BencodeError::InvalidInteger(1)));
+    }
+
+    #[test]
+    fn decode_rejects_integer_overflow() {
+        assert_eq!(
+            decode(b"i9223372036854775808e"),
+            Err(BencodeError::InvalidInteger(1))
+        );
+        assert_eq!(
+            decode(b"i-9223372036854775808e"),
+            Ok(BencodeValue::Integer(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_non_canonical_lengths() {
+        assert_eq!(decode(b"03:abc"), Err(BencodeError::InvalidLength(0)));
+    }
+
+    #[test]
+    fn decode_rejects_unsorted_keys() {
+        assert_eq!(
+            decode(b"d1:bi1e1:ai2ee"),
+            Err(BencodeError::UnsortedKeys(7))
+        );
+        assert_eq!(
+            decode(b"d1:ai1e1:ai2ee"),
+            Err(BencodeError::UnsortedKeys(7))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_truncated_input() {
+        assert_eq!(decode(b"l"), Err(BencodeError::UnexpectedEof));
+        assert_eq!(decode(b"5:abc"), Err(BencodeError::UnexpectedEof));
+        assert_eq!(decode(b"i12"), Err(BencodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn decode_rejects_deep_nesting() {
+        let mut buf = vec![b'l'; 100];
+        buf.extend(vec![b'e'; 100]);
+
+        assert_eq!(decode(&buf), Err(BencodeError::TooDeep));
+    }
+
+    #[test]
+    fn decode_rejects_unknown_type() {
+        assert_eq!(
+            decode(b"x"),
+            Err(BencodeError::UnexpectedByte {
+                byte: b'x',
+                offset: 0
+            })
+        );
+    }
+}
@@ -2,6 +2,33 @@
 //
 // SPDX-License-Identifier: MIT
 
+//! A BitTorrent library.
+//!
+//! # `no_std`
+//!
+//! Turning off the default `std` feature builds the crate with only `core` and `alloc`.
+//! Encoding and decoding messages and handshakes, [`bencode`], the extension handshake
+//! and screening peer IDs all still work. What's left out is whatever needs the standard library:
+//!
+//! - [`pwp::Connection`] and [`pwp::ConnectionRegistry`], which keep time with `Instant`
+//! - [`pwp::Choker`], [`pwp::PeerStats`] and the rest of the choking algorithm
+//! - [`pwp::RequestQueue`] and [`pwp::PieceAssembler`], which use `HashMap`
+//! - [`pwp::BlockingPeer`] and [`pwp::Message::write_to`], which do `std::io`
+//! - [`peer_id::generate_azureus`] and [`peer_id::generate_random`], which use the thread-local RNG;
+//!   their `_with` variants take an RNG and are still available
+//!
+//! The `sha1`, `serde`, `arbitrary`, `tokio` and `mse` features all turn `std` back on.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod bencode;
+#[cfg(feature = "mse")]
+pub mod mse;
+pub mod peer_id;
+#[cfg(feature = "sha1")]
+pub mod piece;
 pub mod pwp;
 
 pub type PeerId = [u8; 20];
@@ -9,3 +36,34 @@ pub type InfoHash = [u8; 20];
 pub type PieceIndex = u32;
 pub type BlockOffset = u32;
 pub type BlockSize = u32;
+
+/// Where a block sits: the piece it belongs to and its offset within that piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockCoord {
+    pub index: PieceIndex,
+    pub offset: BlockOffset,
+}
+
+impl BlockCoord {
+    pub fn new(index: PieceIndex, offset: BlockOffset) -> Self {
+        BlockCoord { index, offset }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BlockCoord;
+    use std::collections::HashMap;
+
+    #[test]
+    fn block_coord_as_map_key() {
+        let mut map = HashMap::new();
+        map.insert(BlockCoord::new(1, 0), "first");
+        map.insert(BlockCoord::new(1, 16384), "second");
+        map.insert(BlockCoord::new(1, 0), "replaced");
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&BlockCoord::new(1, 0)], "replaced");
+        assert_eq!(map.get(&BlockCoord::new(0, 1)), None);
+    }
+}
@@ -0,0 +1,582 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Message Stream Encryption, the obfuscated handshake that can come before the PWP handshake.
+//!
+//! Both sides agree on a key with Diffie-Hellman, prove they know the torrent's info hash,
+//! and pick whether the rest of the connection is RC4-encrypted or plaintext.
+//! The resulting [`MseStream`] is an ordinary byte stream, so the PWP handshake
+//! and everything after it run over it unchanged.
+
+use crate::InfoHash;
+use bitflags::bitflags;
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The 768-bit Diffie-Hellman prime.
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u32 = 2;
+
+/// The length of a public key or shared secret.
+const KEY_LEN: usize = 96;
+/// The length of a private key.
+const PRIVATE_KEY_LEN: usize = 20;
+/// The most padding either side may send.
+const MAX_PAD_LEN: usize = 512;
+/// The verification constant, which decrypts to all zeroes.
+const VC: [u8; 8] = [0; 8];
+/// How much keystream is thrown away before use, since RC4's first bytes are weak.
+const RC4_DISCARD: usize = 1024;
+
+bitflags! {
+    /// How the connection is protected after the handshake.
+    pub struct CryptoMethods: u32 {
+        const PLAINTEXT = 0x01;
+        const RC4 = 0x02;
+    }
+}
+
+/// Errors from the encrypted handshake.
+#[derive(Debug, Error)]
+pub enum MseError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("peer's public key is out of range")]
+    InvalidPublicKey,
+    #[error("couldn't find the peer's handshake within the allowed padding")]
+    SyncNotFound,
+    #[error("verification constant didn't decrypt to zeroes")]
+    InvalidVerification,
+    #[error("peer asked for a torrent we don't have")]
+    UnknownInfoHash,
+    #[error("no encryption method is acceptable to both sides")]
+    NoCommonMethod,
+    #[error("peer selected {0:#x}, which isn't exactly one of the methods we offered")]
+    InvalidSelection(u32),
+    #[error("padding of {0} bytes is longer than allowed")]
+    PaddingTooLong(usize),
+}
+
+/// Connect to a peer: send our public key, prove we know `info_hash`,
+/// and offer the methods in `provide` for the peer to choose from.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &InfoHash,
+    provide: CryptoMethods,
+) -> Result<MseStream<S>, MseError> {
+    let keys = KeyPair::generate();
+    stream.write_all(&keys.public).await?;
+    stream.write_all(&random_padding()).await?;
+    stream.flush().await?;
+
+    let mut their_public = [0; KEY_LEN];
+    stream.read_exact(&mut their_public).await?;
+    let secret = keys.shared_secret(&their_public)?;
+
+    let mut encrypt = Rc4::with_discard(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::with_discard(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut msg = Vec::with_capacity(40 + VC.len() + 8);
+    msg.extend_from_slice(&hash(&[b"req1", &secret]));
+    msg.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut body = Vec::with_capacity(VC.len() + 8);
+    body.extend_from_slice(&VC);
+    body.extend_from_slice(&provide.bits().to_be_bytes());
+    body.extend_from_slice(&0u16.to_be_bytes()); // No PadC.
+    body.extend_from_slice(&0u16.to_be_bytes()); // No initial payload.
+    encrypt.apply(&mut body);
+    msg.extend_from_slice(&body);
+    stream.write_all(&msg).await?;
+    stream.flush().await?;
+
+    // The peer's reply starts after its padding, with the verification constant.
+    let mut expected_vc = VC;
+    decrypt.clone().apply(&mut expected_vc);
+    sync(&mut stream, &expected_vc).await?;
+    decrypt.apply(&mut [0; VC.len()]);
+
+    let mut select = [0; 4];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let select = u32::from_be_bytes(select);
+    let method = CryptoMethods::from_bits(select)
+        .filter(|m| m.bits().count_ones() == 1 && provide.contains(*m))
+        .ok_or(MseError::InvalidSelection(select))?;
+
+    let pad_len = read_pad_len(&mut stream, &mut decrypt).await?;
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    Ok(MseStream::new(
+        stream,
+        method,
+        encrypt,
+        decrypt,
+        BytesMut::new(),
+    ))
+}
+
+/// Accept a peer's encrypted handshake, for one of the torrents in `info_hashes`,
+/// choosing from the methods in `allowed`, preferring RC4.
+///
+/// Returns the stream along with the info hash the peer asked for.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[InfoHash],
+    allowed: CryptoMethods,
+) -> Result<(MseStream<S>, InfoHash), MseError> {
+    let mut their_public = [0; KEY_LEN];
+    stream.read_exact(&mut their_public).await?;
+
+    let keys = KeyPair::generate();
+    let secret = keys.shared_secret(&their_public)?;
+    stream.write_all(&keys.public).await?;
+    stream.write_all(&random_padding()).await?;
+    stream.flush().await?;
+
+    sync(&mut stream, &hash(&[b"req1", &secret])).await?;
+
+    let mut obfuscated = [0; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|ih| xor(&hash(&[b"req2", *ih]), &req3).eq(obfuscated.iter().copied()))
+        .ok_or(MseError::UnknownInfoHash)?;
+
+    let mut decrypt = Rc4::with_discard(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::with_discard(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut header = [0; VC.len() + 4];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..VC.len()] != VC {
+        return Err(MseError::InvalidVerification);
+    }
+    let provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let offered = CryptoMethods::from_bits_truncate(provide) & allowed;
+    let method = if offered.contains(CryptoMethods::RC4) {
+        CryptoMethods::RC4
+    } else if offered.contains(CryptoMethods::PLAINTEXT) {
+        CryptoMethods::PLAINTEXT
+    } else {
+        return Err(MseError::NoCommonMethod);
+    };
+
+    let pad_len = read_pad_len(&mut stream, &mut decrypt).await?;
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    // The initial payload is always encrypted, whichever method is chosen.
+    let mut ia_len = [0; 2];
+    stream.read_exact(&mut ia_len).await?;
+    decrypt.apply(&mut ia_len);
+    let mut initial = BytesMut::zeroed(u16::from_be_bytes(ia_len) as usize);
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let mut reply = Vec::with_capacity(VC.len() + 6);
+    reply.extend_from_slice(&VC);
+    reply.extend_from_slice(&method.bits().to_be_bytes());
+    reply.extend_from_slice(&0u16.to_be_bytes()); // No PadD.
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+    stream.flush().await?;
+
+    Ok((
+        MseStream::new(stream, method, encrypt, decrypt, initial),
+        info_hash,
+    ))
+}
+
+/// A stream after the encrypted handshake, encrypting and decrypting if RC4 was chosen.
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    method: CryptoMethods,
+    /// Our cipher and the peer's, if the connection is encrypted.
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Bytes already decrypted, like the handshake's initial payload, to be read before any more.
+    read_pending: BytesMut,
+    /// Encrypted bytes not yet written to `inner`.
+    write_pending: BytesMut,
+}
+
+impl<S> MseStream<S> {
+    fn new(
+        inner: S,
+        method: CryptoMethods,
+        encrypt: Rc4,
+        decrypt: Rc4,
+        read_pending: BytesMut,
+    ) -> Self {
+        MseStream {
+            inner,
+            method,
+            ciphers: (method == CryptoMethods::RC4).then_some((encrypt, decrypt)),
+            read_pending,
+            write_pending: BytesMut::new(),
+        }
+    }
+
+    /// The method the two sides settled on.
+    pub fn method(&self) -> CryptoMethods {
+        self.method
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_pending.is_empty() {
+            let n = this.read_pending.len().min(buf.remaining());
+            buf.put_slice(&this.read_pending.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((_, decrypt)) = &mut this.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        match &mut this.ciphers {
+            None => Pin::new(&mut this.inner).poll_write(cx, buf),
+            Some((encrypt, _)) => {
+                // Once encrypted, the bytes have to go out, so they're queued rather than retried.
+                this.write_pending.extend_from_slice(buf);
+                encrypt.apply(&mut this.write_pending);
+                if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Our half of a Diffie-Hellman exchange.
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0; PRIVATE_KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime());
+
+        KeyPair {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, their_public: &[u8; KEY_LEN]) -> Result<[u8; KEY_LEN], MseError> {
+        let p = prime();
+        let y = BigUint::from_bytes_be(their_public);
+        // 1 and p - 1 would force the secret to a value anyone could guess.
+        if y <= BigUint::from(1u32) || y >= &p - 1u32 {
+            return Err(MseError::InvalidPublicKey);
+        }
+        Ok(to_key_bytes(&y.modpow(&self.private, &p)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::from_bytes_be(&PRIME)
+}
+
+/// A number below the prime, as big-endian bytes padded to the full key length.
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor<'a>(a: &'a [u8; 20], b: &'a [u8; 20]) -> impl Iterator<Item = u8> + 'a {
+    a.iter().zip(b).map(|(x, y)| x ^ y)
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0; rng.gen_range(0..=MAX_PAD_LEN)];
+    rng.fill_bytes(&mut pad);
+    pad
+}
+
+/// Read up to and including `marker`, which has to start within the peer's padding.
+///
+/// Reads a byte at a time so nothing past the marker is consumed.
+async fn sync<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> Result<(), MseError> {
+    let mut window = Vec::with_capacity(MAX_PAD_LEN + marker.len());
+    while !window.ends_with(marker) {
+        if window.len() == MAX_PAD_LEN + marker.len() {
+            return Err(MseError::SyncNotFound);
+        }
+        window.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+async fn read_pad_len<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    decrypt: &mut Rc4,
+) -> Result<usize, MseError> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    decrypt.apply(&mut len);
+    let len = u16::from_be_bytes(len) as usize;
+    if len > MAX_PAD_LEN {
+        return Err(MseError::PaddingTooLong(len));
+    }
+    Ok(len)
+}
+
+/// The RC4 stream cipher.
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    /// A cipher with the first kilobyte of keystream already thrown away, as MSE requires.
+    fn with_discard(key: &[u8]) -> Self {
+        let mut rc4 = Rc4::new(key);
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+
+    /// Encrypt or decrypt `data` in place.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Don't leak the cipher state into logs.
+        f.write_str("Rc4")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mse::{accept, initiate, CryptoMethods, KeyPair, MseError, Rc4};
+    use crate::pwp::{Handshake, HandshakeFlags, Message};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const INFO_HASH: [u8; 20] = *b"landslide-info-hash!";
+
+    #[test]
+    fn rc4_known_answer() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn both_sides_agree_on_secret() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+
+        assert_eq!(
+            a.shared_secret(&b.public).unwrap(),
+            b.shared_secret(&a.public).unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_trivial_public_key() {
+        let keys = KeyPair::generate();
+        let mut one = [0; 96];
+        one[95] = 1;
+
+        assert!(matches!(
+            keys.shared_secret(&one),
+            Err(MseError::InvalidPublicKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pwp_handshake_over_rc4() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (outgoing, incoming) = tokio::join!(
+            initiate(a, &INFO_HASH, CryptoMethods::RC4 | CryptoMethods::PLAINTEXT),
+            accept(b, &[[0; 20], INFO_HASH], CryptoMethods::all()),
+        );
+        let mut outgoing = outgoing.unwrap();
+        let (mut incoming, info_hash) = incoming.unwrap();
+
+        assert_eq!(info_hash, INFO_HASH);
+        assert_eq!(outgoing.method(), CryptoMethods::RC4);
+        assert_eq!(incoming.method(), CryptoMethods::RC4);
+
+        let ours = Handshake::new(INFO_HASH, *b"-LS0001-initiator000", HandshakeFlags::FAST);
+        let theirs = Handshake::new(INFO_HASH, *b"-LS0001-responder000", HandshakeFlags::DHT);
+        let (got_theirs, got_ours) = tokio::join!(
+            ours.clone().exchange(&mut outgoing),
+            theirs.clone().exchange(&mut incoming),
+        );
+        assert_eq!(got_theirs.unwrap(), theirs);
+        assert_eq!(got_ours.unwrap(), ours);
+
+        let frame = Message::Have(7).serialize();
+        outgoing.write_all(&frame).await.unwrap();
+        outgoing.flush().await.unwrap();
+        let mut received = vec![0; frame.len()];
+        incoming.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, frame);
+
+        // What goes over the wire is encrypted.
+        let mut raw = incoming.inner;
+        outgoing.write_all(&frame).await.unwrap();
+        outgoing.flush().await.unwrap();
+        let mut received = vec![0; frame.len()];
+        raw.read_exact(&mut received).await.unwrap();
+        assert_ne!(received, frame);
+    }
+
+    #[tokio::test]
+    async fn plaintext_when_only_plaintext_allowed() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (outgoing, incoming) = tokio::join!(
+            initiate(a, &INFO_HASH, CryptoMethods::all()),
+            accept(b, &[INFO_HASH], CryptoMethods::PLAINTEXT),
+        );
+        let mut outgoing = outgoing.unwrap();
+        let (incoming, _) = incoming.unwrap();
+
+        assert_eq!(outgoing.method(), CryptoMethods::PLAINTEXT);
+        assert_eq!(incoming.method(), CryptoMethods::PLAINTEXT);
+
+        // After the handshake, bytes go over the wire as they are.
+        let mut raw = incoming.inner;
+        outgoing.write_all(b"hello").await.unwrap();
+        outgoing.flush().await.unwrap();
+        let mut received = [0; 5];
+        raw.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn unknown_torrent() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (_, incoming) = tokio::join!(
+            initiate(a, &INFO_HASH, CryptoMethods::RC4),
+            accept(b, &[[0; 20]], CryptoMethods::all()),
+        );
+
+        assert!(matches!(incoming, Err(MseError::UnknownInfoHash)));
+    }
+
+    #[tokio::test]
+    async fn no_common_method() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (_, incoming) = tokio::join!(
+            initiate(a, &INFO_HASH, CryptoMethods::PLAINTEXT),
+            accept(b, &[INFO_HASH], CryptoMethods::RC4),
+        );
+
+        assert!(matches!(incoming, Err(MseError::NoCommonMethod)));
+    }
+}
@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Generating and screening peer IDs.
+//!
+//! Each function has a `_with` variant that takes the random number generator to use,
+//! so tests can pass a seeded one and get the same ID every time.
+
+use crate::PeerId;
+use alloc::vec::Vec;
+use rand::RngCore;
+
+/// An Azureus-style peer ID, like `-LS0001-` followed by twelve random bytes.
+///
+/// `client` is the two-character client code and `version` is four characters of version number.
+#[cfg(feature = "std")]
+pub fn generate_azureus(client: [u8; 2], version: [u8; 4]) -> PeerId {
+    generate_azureus_with(&mut rand::thread_rng(), client, version)
+}
+
+/// An Azureus-style peer ID using random bytes from `rng`.
+pub fn generate_azureus_with<R: RngCore + ?Sized>(
+    rng: &mut R,
+    client: [u8; 2],
+    version: [u8; 4],
+) -> PeerId {
+    let mut peer_id = [0u8; 20];
+    peer_id[0] = b'-';
+    peer_id[1..3].copy_from_slice(&client);
+    peer_id[3..7].copy_from_slice(&version);
+    peer_id[7] = b'-';
+    rng.fill_bytes(&mut peer_id[8..]);
+    peer_id
+}
+
+/// A peer ID made entirely of random bytes.
+#[cfg(feature = "std")]
+pub fn generate_random() -> PeerId {
+    generate_random_with(&mut rand::thread_rng())
+}
+
+/// A peer ID made entirely of random bytes from `rng`.
+pub fn generate_random_with<R: RngCore + ?Sized>(rng: &mut R) -> PeerId {
+    let mut peer_id = [0u8; 20];
+    rng.fill_bytes(&mut peer_id);
+    peer_id
+}
+
+/// Peer ID prefixes of clients we refuse to talk to.
+///
+/// Check a peer against this once its handshake has been parsed.
+#[derive(Debug, Clone, Default)]
+pub struct PeerIdBlocklist {
+    prefixes: Vec<Vec<u8>>,
+}
+
+impl PeerIdBlocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Block every peer whose ID starts with `prefix`, like `b"-XX"` for an Azureus-style client code.
+    pub fn add_prefix(&mut self, prefix: &[u8]) {
+        self.prefixes.push(prefix.to_vec());
+    }
+
+    pub fn is_blocked(&self, peer_id: &PeerId) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| peer_id.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use crate::peer_id::{generate_azureus, PeerIdBlocklist};
+    use crate::peer_id::{generate_azureus_with, generate_random_with};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn azureus_prefix() {
+        let peer_id = generate_azureus(*b"LS", *b"0001");
+
+        assert_eq!(&peer_id[..8], b"-LS0001-");
+    }
+
+    #[test]
+    fn azureus_is_deterministic_with_seed() {
+        let a = generate_azureus_with(&mut StdRng::seed_from_u64(42), *b"LS", *b"0001");
+        let b = generate_azureus_with(&mut StdRng::seed_from_u64(42), *b"LS", *b"0001");
+        let c = generate_azureus_with(&mut StdRng::seed_from_u64(43), *b"LS", *b"0001");
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(&c[..8], b"-LS0001-");
+    }
+
+    #[test]
+    fn random_is_deterministic_with_seed() {
+        let a = generate_random_with(&mut StdRng::seed_from_u64(42));
+        let b = generate_random_with(&mut StdRng::seed_from_u64(42));
+        let c = generate_random_with(&mut StdRng::seed_from_u64(43));
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn blocklist_blocks_prefix() {
+        let mut blocklist = PeerIdBlocklist::new();
+        blocklist.add_prefix(b"-XL");
+
+        assert!(blocklist.is_blocked(&generate_azureus(*b"XL", *b"0012")));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn blocklist_allows_other_peers() {
+        let mut blocklist = PeerIdBlocklist::new();
+        blocklist.add_prefix(b"-XL");
+
+        assert!(!blocklist.is_blocked(&generate_azureus(*b"LS", *b"0001")));
+        assert!(!PeerIdBlocklist::new().is_blocked(&generate_azureus(*b"XL", *b"0012")));
+    }
+}
@@ -0,0 +1,49 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Checking downloaded pieces against the hashes in the info dictionary.
+
+use sha1::{Digest, Sha1};
+
+/// Whether `data` hashes to `expected`.
+pub fn verify_piece(data: &[u8], expected: &[u8; 20]) -> bool {
+    Sha1::digest(data)[..] == expected[..]
+}
+
+/// Check each piece against its expected hash, returning whether each one matched.
+pub fn verify_pieces<'a>(pieces: impl IntoIterator<Item = (&'a [u8], &'a [u8; 20])>) -> Vec<bool> {
+    pieces
+        .into_iter()
+        .map(|(data, expected)| verify_piece(data, expected))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::piece::{verify_piece, verify_pieces};
+
+    // SHA-1 of "abc", from FIPS 180-1.
+    const ABC_HASH: [u8; 20] = [
+        0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50, 0xc2,
+        0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+    ];
+
+    #[test]
+    fn verify_matching_piece() {
+        assert!(verify_piece(b"abc", &ABC_HASH));
+    }
+
+    #[test]
+    fn verify_corrupt_piece() {
+        assert!(!verify_piece(b"abd", &ABC_HASH));
+        assert!(!verify_piece(b"", &ABC_HASH));
+    }
+
+    #[test]
+    fn verify_several_pieces() {
+        let pieces = [(&b"abc"[..], &ABC_HASH), (&b"xyz"[..], &ABC_HASH)];
+
+        assert_eq!(verify_pieces(pieces), vec![true, false]);
+    }
+}
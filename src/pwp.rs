@@ -4,70 +4,449 @@
 
 //! Implementation of the Peer Wire Protocol (PWP).
 
+use alloc::vec::Vec;
 use bitflags::bitflags;
-use bytes::{Bytes, BytesMut, BufMut};
-use std::convert::TryInto;
+use bytes::{BufMut, Bytes, BytesMut};
+use core::convert::TryInto;
+use core::fmt;
+use core::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(feature = "std")]
+use std::io;
+use thiserror::Error;
 
-#[derive(Debug, Default)]
-pub struct Connection {
-    us_choking: ChokeFlag,
-    us_interested: InterestFlag,
-    them_choking: ChokeFlag,
-    them_interested: InterestFlag,
+#[cfg(feature = "sha1")]
+mod allowed_fast;
+#[cfg(feature = "std")]
+mod assembler;
+mod bitfield;
+#[cfg(feature = "std")]
+mod blocking;
+#[cfg(feature = "std")]
+mod choke;
+#[cfg(feature = "tokio")]
+mod codec;
+#[cfg(feature = "std")]
+mod connection;
+mod extended;
+#[cfg(feature = "arbitrary")]
+mod fuzz;
+#[cfg(test)]
+mod golden;
+#[cfg(feature = "serde")]
+mod inspect;
+mod message_ref;
+#[cfg(test)]
+mod props;
+mod reader;
+#[cfg(feature = "std")]
+mod request_queue;
+#[cfg(feature = "tokio")]
+mod session;
+#[cfg(feature = "sha1")]
+mod ut_metadata;
+
+#[cfg(feature = "sha1")]
+pub use allowed_fast::allowed_fast_set;
+#[cfg(feature = "std")]
+pub use assembler::{AssemblyError, PieceAssembler};
+pub use bitfield::{Bitfield, BitfieldError, BitfieldRef};
+#[cfg(feature = "std")]
+pub use blocking::BlockingPeer;
+#[cfg(feature = "std")]
+pub use choke::{ChokeDecision, Choker, PeerStats, OPTIMISTIC_UNCHOKE_INTERVAL};
+#[cfg(feature = "tokio")]
+pub use codec::{write_initiation, CodecError, PwpCodec};
+#[cfg(feature = "std")]
+pub use connection::{Connection, ConnectionRegistry, DEFAULT_RATE_WINDOW};
+pub use extended::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
+pub use message_ref::MessageRef;
+pub use reader::FrameReader;
+#[cfg(feature = "std")]
+pub use request_queue::RequestQueue;
+#[cfg(feature = "tokio")]
+pub use session::{connect, ConnectError, PeerSession, PeerTransport};
+#[cfg(feature = "sha1")]
+pub use ut_metadata::{
+    MetadataAssembler, MetadataError, MetadataMessage, MAX_METADATA_SIZE, METADATA_PIECE_LEN,
+    UT_METADATA,
+};
+
+/// The state of our download from a single peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadStatus {
+    /// The peer is choking us, so we can't request anything.
+    Choked,
+    /// The peer has unchoked us, but we have no requests outstanding.
+    Idle,
+    /// The peer has unchoked us and we are waiting on requested blocks.
+    Active,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InterestFlag {
     Interested,
+    #[default]
     NotInterested,
 }
 
-impl Default for InterestFlag {
-    fn default() -> Self {
-        InterestFlag::NotInterested
+impl InterestFlag {
+    /// The interest state set by the message with the given ID, if it sets one.
+    pub fn from_message_id(id: u8) -> Option<InterestFlag> {
+        match id {
+            0x02 => Some(InterestFlag::Interested),
+            0x03 => Some(InterestFlag::NotInterested),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ChokeFlag {
+    #[default]
     Choked,
     Unchoked,
 }
 
-impl Default for ChokeFlag {
-    fn default() -> Self {
-        ChokeFlag::Choked
+impl fmt::Display for InterestFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterestFlag::Interested => f.write_str("interested"),
+            InterestFlag::NotInterested => f.write_str("not-interested"),
+        }
+    }
+}
+
+impl ChokeFlag {
+    /// The choke state set by the message with the given ID, if it sets one.
+    pub fn from_message_id(id: u8) -> Option<ChokeFlag> {
+        match id {
+            0x00 => Some(ChokeFlag::Choked),
+            0x01 => Some(ChokeFlag::Unchoked),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ChokeFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChokeFlag::Choked => f.write_str("choked"),
+            ChokeFlag::Unchoked => f.write_str("unchoked"),
+        }
+    }
+}
+
+/// The protocol string sent in every handshake.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// The length of a serialized handshake.
+const HANDSHAKE_LEN: usize = 68;
+
+/// The largest block a peer may request by convention: 16 KiB.
+pub const DEFAULT_MAX_BLOCK_SIZE: u32 = 16 * 1024;
+
+/// The longest message we read by default, not counting the length prefix:
+/// enough for a block of [`DEFAULT_MAX_BLOCK_SIZE`] and its header.
+pub const DEFAULT_MAX_FRAME_LEN: u32 = DEFAULT_MAX_BLOCK_SIZE + 9;
+
+/// The longest message we read by default once the Extension Protocol is negotiated.
+///
+/// A ut_metadata `data` message carries a whole 16 KiB piece after its bencoded dictionary,
+/// so it doesn't fit in [`DEFAULT_MAX_FRAME_LEN`].
+pub const DEFAULT_MAX_EXTENDED_FRAME_LEN: u32 = 2 * DEFAULT_MAX_BLOCK_SIZE;
+
+/// The longest message to read by default from a peer that negotiated `extensions`,
+/// not counting the length prefix.
+pub fn default_max_frame_len(extensions: HandshakeFlags) -> u32 {
+    if extensions.contains(HandshakeFlags::EXTENDED) {
+        DEFAULT_MAX_EXTENDED_FRAME_LEN
+    } else {
+        DEFAULT_MAX_FRAME_LEN
     }
 }
 
+/// The longest message with an ID we don't model that we'll accept, not counting the length prefix.
+///
+/// That's far past any standard message but a bitfield, so a newer extension's messages fit,
+/// while a bogus length is refused before we wait on its body.
+/// The readers also hold every message to a much tighter [`default_max_frame_len`] unless raised.
+pub const MAX_UNKNOWN_MESSAGE_LEN: u32 = 1024 * 1024;
+
 /// The establishing handshake that starts a PWP connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Handshake {
-    flags: HandshakeFlags,
+    reserved: u64,
     info_hash: crate::InfoHash,
     peer_id: crate::PeerId,
 }
 
 bitflags! {
 /// The reserved bits of the handshake, used to flag certain extensions.
-    struct HandshakeFlags: u64 {
+    pub struct HandshakeFlags: u64 {
         const FAST = 0x0000_0000_0000_0400;
         const DHT = 0x0000_0000_0000_0001;
         const EXTENDED = 0x0000_0000_1000_0000;
     }
 }
+
+impl HandshakeFlags {
+    /// The extensions we advertise that the peer does not, e.g. for logging lost capabilities.
+    pub fn missing_from_peer(&self, peer: HandshakeFlags) -> HandshakeFlags {
+        *self - peer
+    }
+
+    /// The extensions both we and the peer advertise, which are the ones the connection may use.
+    pub fn negotiate(&self, peer: &HandshakeFlags) -> HandshakeFlags {
+        *self & *peer
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.contains(HandshakeFlags::FAST)
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.contains(HandshakeFlags::DHT)
+    }
+
+    pub fn supports_extended(&self) -> bool {
+        self.contains(HandshakeFlags::EXTENDED)
+    }
+
+    /// The known extensions these flags advertise.
+    pub fn extensions(&self) -> Vec<Extension> {
+        Extension::ALL
+            .iter()
+            .copied()
+            .filter(|ext| self.contains(ext.flag()))
+            .collect()
+    }
+}
+
+/// An extension that can be advertised in the handshake's reserved bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Extension {
+    /// The DHT (BEP 5), for the `Port` message.
+    Dht,
+    /// The Fast Extension (BEP 6).
+    Fast,
+    /// The Extension Protocol (BEP 10).
+    Extended,
+}
+
+impl Extension {
+    /// Every extension we know about.
+    pub const ALL: [Extension; 3] = [Extension::Dht, Extension::Fast, Extension::Extended];
+
+    /// The reserved bit that advertises this extension.
+    pub fn flag(&self) -> HandshakeFlags {
+        match self {
+            Extension::Dht => HandshakeFlags::DHT,
+            Extension::Fast => HandshakeFlags::FAST,
+            Extension::Extended => HandshakeFlags::EXTENDED,
+        }
+    }
+}
+
+/// Builds [`HandshakeFlags`] by naming extensions instead of combining bits.
+///
+/// ```
+/// use landslide::pwp::{ExtensionSet, HandshakeFlags};
+///
+/// let flags = ExtensionSet::new().fast(true).extended(true).build();
+/// assert_eq!(flags, HandshakeFlags::FAST | HandshakeFlags::EXTENDED);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtensionSet {
+    flags: HandshakeFlags,
+}
+
+impl ExtensionSet {
+    /// A set with no extensions.
+    pub fn new() -> Self {
+        ExtensionSet {
+            flags: HandshakeFlags::empty(),
+        }
+    }
+
+    pub fn dht(self, enabled: bool) -> Self {
+        self.with(Extension::Dht, enabled)
+    }
+
+    pub fn fast(self, enabled: bool) -> Self {
+        self.with(Extension::Fast, enabled)
+    }
+
+    pub fn extended(self, enabled: bool) -> Self {
+        self.with(Extension::Extended, enabled)
+    }
+
+    /// Turn any extension on or off.
+    pub fn with(mut self, ext: Extension, enabled: bool) -> Self {
+        self.flags.set(ext.flag(), enabled);
+        self
+    }
+
+    pub fn build(self) -> HandshakeFlags {
+        self.flags
+    }
+}
+
+impl Default for ExtensionSet {
+    fn default() -> Self {
+        ExtensionSet::new()
+    }
+}
+
+/// Serialized as the reserved bits it sets, as a number.
+#[cfg(feature = "serde")]
+impl serde::Serialize for HandshakeFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.bits())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HandshakeFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(HandshakeFlags::from_bits_truncate)
+    }
+}
+
 impl Handshake {
+    pub fn new(
+        info_hash: crate::InfoHash,
+        peer_id: crate::PeerId,
+        flags: HandshakeFlags,
+    ) -> Handshake {
+        Handshake {
+            reserved: flags.bits(),
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// A handshake advertising no extensions at all, for peers or trackers that require it.
+    pub fn strict(info_hash: crate::InfoHash, peer_id: crate::PeerId) -> Self {
+        Handshake::new(info_hash, peer_id, HandshakeFlags::empty())
+    }
+
+    /// The info hash of the torrent this connection is for.
+    pub fn info_hash(&self) -> &crate::InfoHash {
+        &self.info_hash
+    }
+
+    /// The ID of the peer that sent this handshake.
+    pub fn peer_id(&self) -> &crate::PeerId {
+        &self.peer_id
+    }
+
+    /// The extensions advertised in the reserved bits.
+    ///
+    /// Bits for extensions we don't know about are left out; see [`Handshake::raw_reserved`].
+    pub fn flags(&self) -> HandshakeFlags {
+        HandshakeFlags::from_bits_truncate(self.reserved)
+    }
+
+    /// All of the reserved bits, including any we don't have a flag for.
+    pub fn raw_reserved(&self) -> u64 {
+        self.reserved
+    }
+
     pub fn serialize(&self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(68);
-        buf.put_u8(19);
-        buf.put_slice(b"BitTorrent Protocol");
-        buf.put_u64(self.flags.bits());
-        buf.put_slice(&self.peer_id);
-        buf.put_slice(&self.info_hash);
+        let mut buf = BytesMut::with_capacity(HANDSHAKE_LEN);
+        self.serialize_into(&mut buf);
         buf.freeze()
     }
+
+    /// Append this handshake to `dst`.
+    pub fn serialize_into(&self, dst: &mut BytesMut) {
+        dst.reserve(HANDSHAKE_LEN);
+        dst.put_u8(19);
+        dst.put_slice(PROTOCOL);
+        dst.put_u64(self.reserved);
+        dst.put_slice(&self.info_hash);
+        dst.put_slice(&self.peer_id);
+    }
+
+    /// Parse a handshake received from a peer.
+    pub fn deserialize(buf: &[u8]) -> Result<Handshake, ProtocolError> {
+        if buf.len() < HANDSHAKE_LEN {
+            return Err(ProtocolError::UnexpectedEof);
+        }
+
+        if buf[0] != 19 || &buf[1..20] != PROTOCOL {
+            return Err(ProtocolError::InvalidProtocolString);
+        }
+
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&buf[20..28]);
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&buf[28..48]);
+        let mut peer_id = [0u8; 20];
+        peer_id.copy_from_slice(&buf[48..68]);
+
+        Ok(Handshake {
+            reserved: u64::from_be_bytes(reserved),
+            info_hash,
+            peer_id,
+        })
+    }
+
+    /// Whether this handshake is for a hybrid torrent with the given BitTorrent v2 info hash.
+    ///
+    /// v2 handshakes carry the SHA-256 info hash truncated to 20 bytes (BEP 52).
+    pub fn matches_v2_info_hash(&self, full_sha256: &[u8; 32]) -> bool {
+        self.info_hash[..] == full_sha256[..20]
+    }
+
+    /// Whether this handshake came from ourselves, i.e. we have connected to our own client.
+    pub fn is_self_connection(&self, our_peer_id: &crate::PeerId) -> bool {
+        &self.peer_id == our_peer_id
+    }
+
+    /// Check a peer's handshake is for the torrent we asked about,
+    /// and, if we know who we dialed (like from a tracker), that it came from them.
+    pub fn matches(
+        &self,
+        expected_info_hash: &crate::InfoHash,
+        expected_peer_id: Option<&crate::PeerId>,
+    ) -> Result<(), ProtocolError> {
+        if &self.info_hash != expected_info_hash {
+            return Err(ProtocolError::InfoHashMismatch);
+        }
+        match expected_peer_id {
+            Some(peer_id) if &self.peer_id != peer_id => Err(ProtocolError::PeerIdMismatch),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Handshake(info_hash={} peer_id={})",
+            Hex(&self.info_hash),
+            Hex(&self.peer_id)
+        )
+    }
+}
+
+/// Formats bytes as lowercase hex.
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
 }
 
 /// Messages sent over PWP after the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message {
     KeepAlive,
     Choke,
@@ -75,7 +454,7 @@ pub enum Message {
     Interested,
     Uninterested,
     Have(crate::PieceIndex),
-    Bitfield(Bytes),
+    Bitfield(Bitfield),
     Request {
         index: crate::PieceIndex,
         offset: crate::BlockOffset,
@@ -91,118 +470,725 @@ pub enum Message {
         offset: crate::BlockOffset,
         data: Bytes,
     },
+    /// The UDP port of the peer's DHT node.
+    Port(u16),
+    /// Fast Extension: a piece the peer would like us to download.
+    SuggestPiece {
+        index: crate::PieceIndex,
+    },
+    /// Fast Extension: the peer has every piece, in place of a full bitfield.
+    HaveAll,
+    /// Fast Extension: the peer has no pieces, in place of an empty bitfield.
+    HaveNone,
+    /// Fast Extension: the peer won't fulfill a request.
+    RejectRequest {
+        index: crate::PieceIndex,
+        offset: crate::BlockOffset,
+        length: u32,
+    },
+    /// Fast Extension: a piece we may request even while choked.
+    AllowedFast {
+        index: crate::PieceIndex,
+    },
+    /// Extension Protocol: a message for one of the extensions negotiated in the extended handshake.
+    Extended {
+        ext_id: u8,
+        payload: Bytes,
+    },
+    /// A message whose ID we don't model, like one from an extension newer than us,
+    /// kept as it arrived so it can be inspected or skipped without losing our place in the stream.
+    ///
+    /// It serializes back to exactly the `id` and `payload` it was read with.
+    /// Decoding only produces one for an ID that isn't any other variant's.
+    Unknown {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+/// The type of a message, from the byte that follows its length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageId {
+    Choke = 0x00,
+    Unchoke = 0x01,
+    Interested = 0x02,
+    Uninterested = 0x03,
+    Have = 0x04,
+    Bitfield = 0x05,
+    Request = 0x06,
+    Block = 0x07,
+    Cancel = 0x08,
+    Port = 0x09,
+    SuggestPiece = 0x0D,
+    HaveAll = 0x0E,
+    HaveNone = 0x0F,
+    RejectRequest = 0x10,
+    AllowedFast = 0x11,
+    Extended = 0x14,
+}
+
+impl MessageId {
+    /// The message type with the given wire ID, if there is one.
+    pub fn from_u8(id: u8) -> Option<MessageId> {
+        match id {
+            0x00 => Some(MessageId::Choke),
+            0x01 => Some(MessageId::Unchoke),
+            0x02 => Some(MessageId::Interested),
+            0x03 => Some(MessageId::Uninterested),
+            0x04 => Some(MessageId::Have),
+            0x05 => Some(MessageId::Bitfield),
+            0x06 => Some(MessageId::Request),
+            0x07 => Some(MessageId::Block),
+            0x08 => Some(MessageId::Cancel),
+            0x09 => Some(MessageId::Port),
+            0x0D => Some(MessageId::SuggestPiece),
+            0x0E => Some(MessageId::HaveAll),
+            0x0F => Some(MessageId::HaveNone),
+            0x10 => Some(MessageId::RejectRequest),
+            0x11 => Some(MessageId::AllowedFast),
+            0x14 => Some(MessageId::Extended),
+            _ => None,
+        }
+    }
+
+    /// The extension that has to be negotiated before this message may be sent, if any.
+    pub fn extension(&self) -> Option<HandshakeFlags> {
+        match self {
+            MessageId::Port => Some(HandshakeFlags::DHT),
+            MessageId::SuggestPiece
+            | MessageId::HaveAll
+            | MessageId::HaveNone
+            | MessageId::RejectRequest
+            | MessageId::AllowedFast => Some(HandshakeFlags::FAST),
+            MessageId::Extended => Some(HandshakeFlags::EXTENDED),
+            _ => None,
+        }
+    }
+}
+
+/// Errors from building a `Request` message.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("block offset plus length overflows a u32")]
+    Overflow,
+}
+
+/// Errors from encoding a message.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EncodeError {
+    #[error("message needs {needed} bytes but the buffer only has {available}")]
+    BufferTooSmall { needed: usize, available: usize },
+    #[error("payload is too big to fit in a message")]
+    PayloadTooLarge,
+    /// An `Unknown` message was given an ID that another variant models,
+    /// so it would be read back as a different message.
+    #[error("message id {0} is already modelled, so it can't be sent as an unknown message")]
+    ModelledId(u8),
+}
+
+/// Errors from parsing anything a peer sends us, from the handshake on.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("input ended before the handshake did")]
+    UnexpectedEof,
+    #[error("peer is not speaking the BitTorrent protocol")]
+    InvalidProtocolString,
+    /// The length prefix doesn't fit the message ID.
+    /// `actual` is the length that message takes, or its minimum for variable-length ones.
+    #[error("length prefix says {declared} bytes but the message takes {actual}")]
+    LengthMismatch { declared: u32, actual: u32 },
+    #[error("message payload is larger than any valid message's")]
+    PayloadTooLarge,
+    #[error("compact peer list is {0} bytes, which is not a multiple of 6")]
+    InvalidCompactPeers(usize),
+    #[error("message id {0} belongs to an extension that wasn't negotiated")]
+    ExtensionNotNegotiated(u8),
+    #[error("extended handshake is not a valid bencoded dictionary")]
+    InvalidExtendedHandshake,
+    #[error("ut_metadata message is malformed")]
+    InvalidMetadataMessage,
+    #[error("port message advertises port 0")]
+    InvalidPort,
+    #[error("requested block of {length} bytes is larger than the limit of {max}")]
+    BlockTooLarge { length: u32, max: u32 },
+    #[error("requested block is empty")]
+    EmptyBlock,
+    #[error("partial message wasn't completed in time")]
+    StalledFrame,
+    #[error("{buffered} bytes are buffered, over the limit of {limit}")]
+    BufferLimitExceeded { buffered: usize, limit: usize },
+    #[error("peer's handshake is for a different torrent")]
+    InfoHashMismatch,
+    #[error("peer's handshake has a different peer ID than expected")]
+    PeerIdMismatch,
+    #[error("bitfield doesn't fit the torrent: {0}")]
+    InvalidBitfield(#[from] BitfieldError),
 }
 
 impl Message {
-    /// Serialize this message.
-    pub fn serialize(self) -> Bytes {
+    /// Parse one message off the front of `buf`, assuming no extensions were negotiated.
+    ///
+    /// Returns `Ok(None)` without consuming anything if `buf` doesn't hold a complete message yet.
+    pub fn deserialize(buf: &mut Bytes) -> Result<Option<Message>, ProtocolError> {
+        Message::deserialize_with(buf, HandshakeFlags::empty())
+    }
+
+    /// Parse one message off the front of `buf`, accepting messages from the negotiated `extensions`.
+    ///
+    /// Returns `Ok(None)` without consuming anything if `buf` doesn't hold a complete message yet.
+    pub fn deserialize_with(
+        buf: &mut Bytes,
+        extensions: HandshakeFlags,
+    ) -> Result<Option<Message>, ProtocolError> {
+        match frame_len(buf, extensions)? {
+            Some(len) => Ok(Some(Message::from_frame(buf.split_to(len)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Parse a whole frame, length prefix included, that has already been checked by `frame_len`.
+    ///
+    /// Payloads share `frame`'s memory rather than being copied.
+    fn from_frame(frame: Bytes) -> Message {
+        MessageRef::from_frame(&frame).to_message(|payload| frame.slice_ref(payload))
+    }
+
+    /// Build a `Request`, making sure the requested block doesn't extend past `u32::MAX`.
+    pub fn request(
+        index: crate::PieceIndex,
+        offset: crate::BlockOffset,
+        length: crate::BlockSize,
+    ) -> Result<Message, RequestError> {
+        offset.checked_add(length).ok_or(RequestError::Overflow)?;
+
+        Ok(Message::Request {
+            index,
+            offset,
+            length,
+        })
+    }
+
+    /// The `Request`s for every block of a piece, in order, with a short last block if needed.
+    ///
+    /// Panics if `block_size` is zero.
+    pub fn request_blocks(
+        index: crate::PieceIndex,
+        piece_len: u32,
+        block_size: crate::BlockSize,
+    ) -> impl Iterator<Item = Message> {
+        assert!(block_size > 0, "block size must be nonzero");
+        (0..piece_len)
+            .step_by(block_size as usize)
+            .map(move |offset| Message::Request {
+                index,
+                offset,
+                length: block_size.min(piece_len - offset),
+            })
+    }
+
+    /// Check a `Request` or `Cancel` from a peer before acting on it.
+    ///
+    /// The block must be non-empty and no longer than `max_block`, usually [`DEFAULT_MAX_BLOCK_SIZE`].
+    /// Other messages are always valid.
+    pub fn validate(&self, max_block: u32) -> Result<(), ProtocolError> {
         match self {
-            Message::KeepAlive => Bytes::from(vec![0x00, 0x00, 0x00, 0x00]),
-            Message::Choke => Bytes::from(vec![0x00, 0x00, 0x00, 0x01, 0x00]),
-            Message::Unchoke => Bytes::from(vec![0x00, 0x00, 0x00, 0x01, 0x01]),
-            Message::Interested => Bytes::from(vec![0x00, 0x00, 0x00, 0x01, 0x02]),
-            Message::Uninterested => Bytes::from(vec![0x00, 0x00, 0x00, 0x01, 0x03]),
-            Message::Have(index) => {
-                let mut buf = BytesMut::with_capacity(9);
-                buf.put_u32(5);
-                buf.put_u8(0x04);
-                buf.put_u32(index);
-                buf.freeze()
+            Message::Request { length, .. } | Message::Cancel { length, .. } => {
+                if *length == 0 {
+                    Err(ProtocolError::EmptyBlock)
+                } else if *length > max_block {
+                    Err(ProtocolError::BlockTooLarge {
+                        length: *length,
+                        max: max_block,
+                    })
+                } else {
+                    Ok(())
+                }
             }
-            Message::Bitfield(b) => {
-                let len = b.len() + 1;
-                let mut buf = BytesMut::with_capacity(len + 4);
-                buf.put_u32(len.try_into().expect("Bitfield is too big to encode in a bitfield message."));
-                buf.put_u8(0x05);
-                buf.put(b);
-                buf.freeze()
+            _ => Ok(()),
+        }
+    }
+
+    /// Serialize this message.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `Bitfield` or `Block` payload is too big for the message's length prefix,
+    /// or if it's an `Unknown` message with an ID that another variant models.
+    /// Use [`Message::try_serialize`] for messages you don't control.
+    pub fn serialize(self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.serialize_into(&mut buf);
+        buf.freeze()
+    }
+
+    /// Serialize this message, failing under the same conditions [`Message::serialize`] panics.
+    pub fn try_serialize(self) -> Result<Bytes, EncodeError> {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.try_serialize_into(&mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Append this message to `dst`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`Message::serialize`].
+    pub fn serialize_into(self, dst: &mut BytesMut) {
+        self.try_serialize_into(dst)
+            .expect("Message can't be encoded.")
+    }
+
+    /// Append this message to `dst`, failing under the same conditions [`Message::serialize`] panics.
+    ///
+    /// Nothing is written if this fails.
+    pub fn try_serialize_into(self, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let len = self.body_len();
+        let prefix = self.length_prefix()?;
+        dst.reserve(len + 4);
+        self.put_frame(prefix, dst);
+        Ok(())
+    }
+
+    /// The type of this message, or `None` for a keep-alive, which has no ID,
+    /// and for an `Unknown` message, whose ID isn't one we model.
+    pub fn id(&self) -> Option<MessageId> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => MessageId::Choke,
+            Message::Unchoke => MessageId::Unchoke,
+            Message::Interested => MessageId::Interested,
+            Message::Uninterested => MessageId::Uninterested,
+            Message::Have(_) => MessageId::Have,
+            Message::Bitfield(_) => MessageId::Bitfield,
+            Message::Request { .. } => MessageId::Request,
+            Message::Cancel { .. } => MessageId::Cancel,
+            Message::Block { .. } => MessageId::Block,
+            Message::Port(_) => MessageId::Port,
+            Message::SuggestPiece { .. } => MessageId::SuggestPiece,
+            Message::HaveAll => MessageId::HaveAll,
+            Message::HaveNone => MessageId::HaveNone,
+            Message::RejectRequest { .. } => MessageId::RejectRequest,
+            Message::AllowedFast { .. } => MessageId::AllowedFast,
+            Message::Extended { .. } => MessageId::Extended,
+            Message::Unknown { .. } => return None,
+        })
+    }
+
+    /// The length prefix for this message, checking it can be encoded at all.
+    fn length_prefix(&self) -> Result<u32, EncodeError> {
+        if let Message::Unknown { id, .. } = self {
+            if MessageId::from_u8(*id).is_some() {
+                return Err(EncodeError::ModelledId(*id));
             }
+        }
+        length_prefix(self.body_len())
+    }
+
+    /// The ID byte this message goes out with, or `None` for a keep-alive.
+    fn id_byte(&self) -> Option<u8> {
+        match self {
+            Message::Unknown { id, .. } => Some(*id),
+            _ => self.id().map(|id| id as u8),
+        }
+    }
+
+    /// How many bytes this message takes on the wire, length prefix included.
+    pub fn encoded_len(&self) -> usize {
+        self.body_len() + 4
+    }
+
+    /// The length of this message after its length prefix.
+    fn body_len(&self) -> usize {
+        match self {
+            Message::KeepAlive => 0,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::Uninterested
+            | Message::HaveAll
+            | Message::HaveNone => 1,
+            Message::Have(_) | Message::SuggestPiece { .. } | Message::AllowedFast { .. } => 5,
+            Message::Bitfield(b) => b.as_bytes().len() + 1,
+            Message::Port(_) => 3,
+            Message::Request { .. } | Message::Cancel { .. } | Message::RejectRequest { .. } => 13,
+            Message::Block { data, .. } => data.len() + 9,
+            Message::Extended { payload, .. } => payload.len() + 2,
+            Message::Unknown { payload, .. } => payload.len() + 1,
+        }
+    }
+
+    /// Write the whole frame, which `buf` must have room for.
+    fn put_frame<B: BufMut>(&self, prefix: u32, buf: &mut B) {
+        buf.put_u32(prefix);
+        if let Some(id) = self.id_byte() {
+            buf.put_u8(id);
+        }
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::Uninterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+            Message::Have(index)
+            | Message::SuggestPiece { index }
+            | Message::AllowedFast { index } => buf.put_u32(*index),
+            Message::Bitfield(b) => buf.put_slice(b.as_bytes()),
             Message::Request {
                 index,
                 offset,
                 length,
-            } => {
-                let mut buf = BytesMut::with_capacity(17);
-                buf.put_u32(13);
-                buf.put_u8(0x06);
-                buf.put_u32(index);
-                buf.put_u32(offset);
-                buf.put_u32(length);
-                buf.freeze()
             }
-            Message::Cancel {
+            | Message::Cancel {
+                index,
+                offset,
+                length,
+            }
+            | Message::RejectRequest {
                 index,
                 offset,
                 length,
             } => {
-                let mut buf = BytesMut::with_capacity(17);
-                buf.put_u32(13);
-                buf.put_u8(0x08);
-                buf.put_u32(index);
-                buf.put_u32(offset);
-                buf.put_u32(length);
-                buf.freeze()
+                buf.put_u32(*index);
+                buf.put_u32(*offset);
+                buf.put_u32(*length);
             }
             Message::Block {
                 index,
                 offset,
                 data,
             } => {
-                let len = data.len() + 9;
-                let mut buf = BytesMut::with_capacity(len + 4);
-                buf.put_u32(len.try_into().expect("Data is too big to fit in a block message."));
-                buf.put_u8(0x07);
-                buf.put_u32(index);
-                buf.put_u32(offset);
-                buf.put(data);
-                buf.freeze()
+                buf.put_u32(*index);
+                buf.put_u32(*offset);
+                buf.put_slice(data);
+            }
+            Message::Port(port) => buf.put_u16(*port),
+            Message::Extended { ext_id, payload } => {
+                buf.put_u8(*ext_id);
+                buf.put_slice(payload);
             }
+            Message::Unknown { payload, .. } => buf.put_slice(payload),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::pwp::Handshake;
-    use crate::pwp::HandshakeFlags;
-    use crate::pwp::Message;
-    use bytes::Bytes;
+    /// Encode this message into the start of `dst`, returning the number of bytes written.
+    ///
+    /// This doesn't allocate, so control messages can be encoded into a buffer on the stack.
+    pub fn encode_to_slice(&self, dst: &mut [u8]) -> Result<usize, EncodeError> {
+        let len = self.body_len();
+        let prefix = self.length_prefix()?;
+        let needed = len + 4;
 
-    #[test]
-    fn serialize_handshake() {
-        let handshake = Handshake {
-            flags: HandshakeFlags::FAST | HandshakeFlags::DHT,
-            peer_id: *b"Landslide Experiment",
-            info_hash: *b"12345678901234567890",
-        };
+        if dst.len() < needed {
+            return Err(EncodeError::BufferTooSmall {
+                needed,
+                available: dst.len(),
+            });
+        }
 
-        let buf = handshake.serialize();
+        self.put_frame(prefix, &mut &mut dst[..needed]);
 
-        assert_eq!(buf.len(), 68);
+        Ok(needed)
     }
 
-    #[test]
-    fn serialize_keepalive() {
-        let msg = Message::KeepAlive;
-        let buf = msg.serialize();
+    /// Write this message to `w`.
+    ///
+    /// The whole frame is assembled first and handed over in a single `write_all`,
+    /// so a `Block` doesn't cost one write for its header and another for its data.
+    #[cfg(feature = "std")]
+    pub fn write_to<W: io::Write>(self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.serialize())
+    }
+}
 
-        assert_eq!(buf.len(), 4);
-        assert_eq!(buf[0], 0x00);
-        assert_eq!(buf[1], 0x00);
-        assert_eq!(buf[2], 0x00);
-        assert_eq!(buf[3], 0x00);
+/// A one-line summary that leaves out payload bytes, for logging.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::KeepAlive => f.write_str("KeepAlive"),
+            Message::Choke => f.write_str("Choke"),
+            Message::Unchoke => f.write_str("Unchoke"),
+            Message::Interested => f.write_str("Interested"),
+            Message::Uninterested => f.write_str("Uninterested"),
+            Message::Have(index) => write!(f, "Have(piece={})", index),
+            Message::Bitfield(b) => write!(
+                f,
+                "Bitfield({} pieces, {} set)",
+                b.piece_count(),
+                b.count_ones()
+            ),
+            Message::Request {
+                index,
+                offset,
+                length,
+            } => write!(f, "Request(piece={} off={} len={})", index, offset, length),
+            Message::Cancel {
+                index,
+                offset,
+                length,
+            } => write!(f, "Cancel(piece={} off={} len={})", index, offset, length),
+            Message::Block {
+                index,
+                offset,
+                data,
+            } => write!(
+                f,
+                "Block(piece={} off={} {} bytes)",
+                index,
+                offset,
+                data.len()
+            ),
+            Message::Port(port) => write!(f, "Port({})", port),
+            Message::SuggestPiece { index } => write!(f, "SuggestPiece(piece={})", index),
+            Message::HaveAll => f.write_str("HaveAll"),
+            Message::HaveNone => f.write_str("HaveNone"),
+            Message::RejectRequest {
+                index,
+                offset,
+                length,
+            } => write!(
+                f,
+                "RejectRequest(piece={} off={} len={})",
+                index, offset, length
+            ),
+            Message::AllowedFast { index } => write!(f, "AllowedFast(piece={})", index),
+            Message::Extended { ext_id, payload } => {
+                write!(f, "Extended(id={} {} bytes)", ext_id, payload.len())
+            }
+            Message::Unknown { id, payload } => {
+                write!(f, "Unknown(id={} {} bytes)", id, payload.len())
+            }
+        }
     }
-    #[test]
-    fn serialize_choke() {
-        let msg = Message::Choke;
-        let buf = msg.serialize();
+}
 
-        assert_eq!(buf.len(), 5);
-        assert_eq!(buf[0], 0x00);
-        assert_eq!(buf[1], 0x00);
-        assert_eq!(buf[2], 0x00);
-        assert_eq!(buf[3], 0x01);
-        assert_eq!(buf[4], 0x00);
+/// Check the header of the frame at the front of `buf`.
+///
+/// Returns the total length of the frame once all of it has arrived.
+fn frame_len(buf: &[u8], extensions: HandshakeFlags) -> Result<Option<usize>, ProtocolError> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+
+    if len == 0 {
+        return Ok(Some(4));
+    }
+
+    // Check the ID up front, so a bad length doesn't wait on a body that may never arrive.
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    // An ID we don't model may have any length, and its frame becomes a `Message::Unknown`.
+    let id = MessageId::from_u8(buf[4]);
+    match id {
+        Some(id) => check_header(id, len, extensions)?,
+        None if len > MAX_UNKNOWN_MESSAGE_LEN => return Err(ProtocolError::PayloadTooLarge),
+        None => {}
+    }
+
+    let frame_len = (len as usize)
+        .checked_add(4)
+        .ok_or(ProtocolError::PayloadTooLarge)?;
+    if buf.len() < frame_len {
+        return Ok(None);
+    }
+
+    // A node can't be contacted on port 0.
+    if id == Some(MessageId::Port) && buf[5..7] == [0, 0] {
+        return Err(ProtocolError::InvalidPort);
+    }
+
+    Ok(Some(frame_len))
+}
+
+/// Check a message's ID was negotiated and its length prefix fits it.
+fn check_header(id: MessageId, len: u32, extensions: HandshakeFlags) -> Result<(), ProtocolError> {
+    if id.extension().is_some_and(|ext| !extensions.contains(ext)) {
+        return Err(ProtocolError::ExtensionNotNegotiated(id as u8));
+    }
+    let (actual, valid_len) = match id {
+        MessageId::Choke
+        | MessageId::Unchoke
+        | MessageId::Interested
+        | MessageId::Uninterested
+        | MessageId::HaveAll
+        | MessageId::HaveNone => (1, len == 1),
+        MessageId::Have | MessageId::SuggestPiece | MessageId::AllowedFast => (5, len == 5),
+        MessageId::Bitfield => {
+            if len as usize - 1 > Bitfield::byte_len(u32::MAX) {
+                return Err(ProtocolError::PayloadTooLarge);
+            }
+            (len, true)
+        }
+        MessageId::Request | MessageId::Cancel | MessageId::RejectRequest => (13, len == 13),
+        MessageId::Block => (9, len >= 9),
+        MessageId::Port => (3, len == 3),
+        MessageId::Extended => (2, len >= 2),
+    };
+    if !valid_len {
+        return Err(ProtocolError::LengthMismatch {
+            declared: len,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// The length prefix for a message body of `len` bytes.
+fn length_prefix(len: usize) -> Result<u32, EncodeError> {
+    len.try_into().map_err(|_| EncodeError::PayloadTooLarge)
+}
+
+/// Whether a download should switch to endgame mode and request its last blocks from every peer.
+///
+/// That's once every block still missing has been requested,
+/// or once no more than `threshold` blocks are missing at all.
+pub fn should_enter_endgame(
+    blocks_remaining: u32,
+    blocks_outstanding: u32,
+    threshold: u32,
+) -> bool {
+    blocks_remaining > 0
+        && (blocks_outstanding >= blocks_remaining || blocks_remaining <= threshold)
+}
+
+/// Serialize several messages back-to-back into one buffer, so they can go out in a single write.
+///
+/// # Panics
+///
+/// Panics under the same conditions as [`Message::serialize`].
+pub fn encode_batch(messages: impl IntoIterator<Item = Message>) -> Bytes {
+    let mut buf = BytesMut::new();
+    encode_batch_into(messages, &mut buf);
+    buf.freeze()
+}
+
+/// Append several messages to `dst`, back-to-back.
+///
+/// # Panics
+///
+/// Panics under the same conditions as [`Message::serialize`].
+pub fn encode_batch_into(messages: impl IntoIterator<Item = Message>, dst: &mut BytesMut) {
+    try_encode_batch_into(messages, dst).expect("Message can't be encoded.")
+}
+
+/// Append several messages to `dst`, back-to-back,
+/// failing under the same conditions [`Message::serialize`] panics.
+///
+/// If any message can't be encoded, `dst` is left as it was.
+pub fn try_encode_batch_into(
+    messages: impl IntoIterator<Item = Message>,
+    dst: &mut BytesMut,
+) -> Result<(), EncodeError> {
+    let start = dst.len();
+    for msg in messages {
+        if let Err(e) = msg.try_serialize_into(dst) {
+            dst.truncate(start);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Produce one `Have` message for each of the given pieces.
+pub fn have_messages(
+    indices: impl IntoIterator<Item = crate::PieceIndex>,
+) -> impl Iterator<Item = Message> {
+    indices.into_iter().map(Message::Have)
+}
+
+/// Parse a compact IPv4 peer list, as returned by trackers, into addresses.
+///
+/// Each peer is six bytes: four for the IP address, then two for the port, both big-endian.
+pub fn parse_compact_peers(data: &[u8]) -> Result<Vec<SocketAddrV4>, ProtocolError> {
+    if !data.len().is_multiple_of(6) {
+        return Err(ProtocolError::InvalidCompactPeers(data.len()));
+    }
+
+    let peers = data
+        .chunks_exact(6)
+        .map(|peer| {
+            let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+            let port = u16::from_be_bytes([peer[4], peer[5]]);
+            SocketAddrV4::new(ip, port)
+        })
+        .collect();
+
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::default_max_frame_len;
+    use crate::pwp::encode_batch;
+    use crate::pwp::encode_batch_into;
+    use crate::pwp::frame_len;
+    use crate::pwp::have_messages;
+    use crate::pwp::length_prefix;
+    use crate::pwp::parse_compact_peers;
+    use crate::pwp::should_enter_endgame;
+    use crate::pwp::try_encode_batch_into;
+    use crate::pwp::Bitfield;
+    use crate::pwp::ChokeFlag;
+    use crate::pwp::EncodeError;
+    use crate::pwp::Extension;
+    use crate::pwp::ExtensionSet;
+    use crate::pwp::Handshake;
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::InterestFlag;
+    use crate::pwp::Message;
+    use crate::pwp::MessageId;
+    use crate::pwp::MessageRef;
+    use crate::pwp::ProtocolError;
+    use crate::pwp::RequestError;
+    use crate::pwp::DEFAULT_MAX_BLOCK_SIZE;
+    use crate::pwp::DEFAULT_MAX_EXTENDED_FRAME_LEN;
+    use crate::pwp::DEFAULT_MAX_FRAME_LEN;
+    use crate::pwp::MAX_UNKNOWN_MESSAGE_LEN;
+    use bytes::{Bytes, BytesMut};
+    use std::collections::HashSet;
+    #[cfg(feature = "std")]
+    use std::io;
+
+    #[test]
+    fn serialize_handshake() {
+        let handshake = Handshake {
+            reserved: (HandshakeFlags::FAST | HandshakeFlags::DHT).bits(),
+            peer_id: *b"Landslide Experiment",
+            info_hash: *b"12345678901234567890",
+        };
+
+        let buf = handshake.serialize();
+
+        assert_eq!(buf.len(), 68);
+    }
+
+    #[test]
+    fn serialize_keepalive() {
+        let msg = Message::KeepAlive;
+        let buf = msg.serialize();
+
+        assert_eq!(buf.len(), 4);
+        assert_eq!(buf[0], 0x00);
+        assert_eq!(buf[1], 0x00);
+        assert_eq!(buf[2], 0x00);
+        assert_eq!(buf[3], 0x00);
+    }
+    #[test]
+    fn serialize_choke() {
+        let msg = Message::Choke;
+        let buf = msg.serialize();
+
+        assert_eq!(buf.len(), 5);
+        assert_eq!(buf[0], 0x00);
+        assert_eq!(buf[1], 0x00);
+        assert_eq!(buf[2], 0x00);
+        assert_eq!(buf[3], 0x01);
+        assert_eq!(buf[4], 0x00);
     }
 
     #[test]
@@ -265,7 +1251,7 @@ mod tests {
 
     #[test]
     fn serialize_bitfield() {
-        let msg = Message::Bitfield(Bytes::from(vec![0xFF, 0xFF, 0xFF]));
+        let msg = Message::Bitfield(Bitfield::from_bytes(&[0xFF, 0xFF, 0xFF], 24).unwrap());
         let buf = msg.serialize();
 
         assert_eq!(buf.len(), 8);
@@ -381,4 +1367,1240 @@ mod tests {
         assert_eq!(buf[17], 23);
         assert_eq!(buf[18], 42);
     }
+
+    #[test]
+    fn handshake_dedup() {
+        let handshake = Handshake {
+            reserved: HandshakeFlags::FAST.bits(),
+            peer_id: *b"Landslide Experiment",
+            info_hash: *b"12345678901234567890",
+        };
+        let other_peer = Handshake {
+            peer_id: *b"Another Experiment!!",
+            ..handshake.clone()
+        };
+
+        let mut seen = HashSet::new();
+
+        assert!(seen.insert(handshake.clone()));
+        assert!(seen.insert(other_peer));
+        assert!(!seen.insert(handshake));
+    }
+
+    #[test]
+    fn endgame_at_threshold() {
+        assert!(should_enter_endgame(20, 0, 20));
+        assert!(!should_enter_endgame(21, 0, 20));
+    }
+
+    #[test]
+    fn endgame_when_everything_is_requested() {
+        assert!(should_enter_endgame(100, 100, 20));
+        assert!(!should_enter_endgame(100, 99, 20));
+    }
+
+    #[test]
+    fn no_endgame_when_done() {
+        assert!(!should_enter_endgame(0, 0, 20));
+    }
+
+    #[test]
+    fn validate_request_sizes() {
+        let request = |length| Message::Request {
+            index: 0,
+            offset: 0,
+            length,
+        };
+
+        assert_eq!(request(16384).validate(DEFAULT_MAX_BLOCK_SIZE), Ok(()));
+        assert_eq!(
+            request(16385).validate(DEFAULT_MAX_BLOCK_SIZE),
+            Err(ProtocolError::BlockTooLarge {
+                length: 16385,
+                max: 16384
+            })
+        );
+        assert_eq!(
+            request(0).validate(DEFAULT_MAX_BLOCK_SIZE),
+            Err(ProtocolError::EmptyBlock)
+        );
+        assert_eq!(request(32768).validate(32768), Ok(()));
+    }
+
+    #[test]
+    fn validate_cancel_size() {
+        let cancel = Message::Cancel {
+            index: 0,
+            offset: 0,
+            length: 1 << 20,
+        };
+
+        assert!(cancel.validate(DEFAULT_MAX_BLOCK_SIZE).is_err());
+        assert_eq!(Message::Have(1).validate(DEFAULT_MAX_BLOCK_SIZE), Ok(()));
+    }
+
+    fn request_lengths(piece_len: u32, block_size: u32) -> Vec<(u32, u32)> {
+        Message::request_blocks(7, piece_len, block_size)
+            .map(|msg| match msg {
+                Message::Request {
+                    index: 7,
+                    offset,
+                    length,
+                } => (offset, length),
+                other => panic!("expected a request, got {}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn request_blocks_even_piece() {
+        assert_eq!(
+            request_lengths(64 * 1024, DEFAULT_MAX_BLOCK_SIZE),
+            vec![(0, 16384), (16384, 16384), (32768, 16384), (49152, 16384)]
+        );
+    }
+
+    #[test]
+    fn request_blocks_short_last_block() {
+        assert_eq!(
+            request_lengths(40000, DEFAULT_MAX_BLOCK_SIZE),
+            vec![(0, 16384), (16384, 16384), (32768, 7232)]
+        );
+    }
+
+    #[test]
+    fn request_blocks_piece_smaller_than_block() {
+        assert_eq!(
+            request_lengths(1000, DEFAULT_MAX_BLOCK_SIZE),
+            vec![(0, 1000)]
+        );
+        assert_eq!(request_lengths(0, DEFAULT_MAX_BLOCK_SIZE), vec![]);
+    }
+
+    #[test]
+    fn encode_batch_decodes_in_order() {
+        let msgs = every_variant();
+
+        let buf = encode_batch(msgs.clone());
+
+        let mut rest = &buf[..];
+        let mut decoded = Vec::new();
+        while let Some(msg) = MessageRef::parse_with(rest, HandshakeFlags::all()).unwrap() {
+            rest = &rest[msg.encoded_len()..];
+            decoded.push(msg.to_owned());
+        }
+        assert!(rest.is_empty());
+        assert_eq!(decoded, msgs);
+    }
+
+    #[test]
+    fn encode_batch_into_appends() {
+        let mut buf = BytesMut::from(&b"already here"[..]);
+
+        encode_batch_into(
+            std::iter::once(Message::Unchoke).chain(have_messages(vec![3, 1])),
+            &mut buf,
+        );
+
+        let mut expected = b"already here".to_vec();
+        expected.extend_from_slice(&Message::Unchoke.serialize());
+        expected.extend_from_slice(&Message::Have(3).serialize());
+        expected.extend_from_slice(&Message::Have(1).serialize());
+        assert_eq!(&buf[..], &expected[..]);
+    }
+
+    #[test]
+    fn try_encode_batch_into_leaves_dst_on_error() {
+        let mut buf = BytesMut::from(&b"already here"[..]);
+        let msgs = vec![
+            Message::Unchoke,
+            Message::Unknown {
+                id: 0x04,
+                payload: Bytes::from_static(&[0, 0, 0, 1]),
+            },
+            Message::Have(1),
+        ];
+
+        assert_eq!(
+            try_encode_batch_into(msgs, &mut buf),
+            Err(EncodeError::ModelledId(0x04))
+        );
+        assert_eq!(&buf[..], b"already here");
+    }
+
+    #[test]
+    fn have_messages_in_order() {
+        let indices: Vec<u32> = have_messages(vec![3, 1, 4])
+            .map(|msg| match msg {
+                Message::Have(index) => index,
+                _ => panic!("expected only have messages"),
+            })
+            .collect();
+
+        assert_eq!(indices, vec![3, 1, 4]);
+    }
+
+    #[test]
+    fn choke_flag_from_message_id() {
+        assert!(matches!(
+            ChokeFlag::from_message_id(0x00),
+            Some(ChokeFlag::Choked)
+        ));
+        assert!(matches!(
+            ChokeFlag::from_message_id(0x01),
+            Some(ChokeFlag::Unchoked)
+        ));
+        assert!(ChokeFlag::from_message_id(0x02).is_none());
+    }
+
+    #[test]
+    fn interest_flag_from_message_id() {
+        assert!(matches!(
+            InterestFlag::from_message_id(0x02),
+            Some(InterestFlag::Interested)
+        ));
+        assert!(matches!(
+            InterestFlag::from_message_id(0x03),
+            Some(InterestFlag::NotInterested)
+        ));
+        assert!(InterestFlag::from_message_id(0x01).is_none());
+    }
+
+    #[test]
+    fn detect_self_connection() {
+        let handshake = Handshake {
+            reserved: 0,
+            peer_id: *b"Landslide Experiment",
+            info_hash: *b"12345678901234567890",
+        };
+
+        assert!(handshake.is_self_connection(b"Landslide Experiment"));
+        assert!(!handshake.is_self_connection(b"Another Experiment!!"));
+    }
+
+    #[test]
+    fn match_expected_handshake() {
+        let handshake = Handshake {
+            reserved: 0,
+            peer_id: *b"Landslide Experiment",
+            info_hash: *b"12345678901234567890",
+        };
+
+        assert_eq!(handshake.matches(b"12345678901234567890", None), Ok(()));
+        assert_eq!(
+            handshake.matches(b"12345678901234567890", Some(b"Landslide Experiment")),
+            Ok(())
+        );
+        assert_eq!(
+            handshake.matches(b"09876543210987654321", Some(b"Landslide Experiment")),
+            Err(ProtocolError::InfoHashMismatch)
+        );
+        assert_eq!(
+            handshake.matches(b"12345678901234567890", Some(b"Another Experiment!!")),
+            Err(ProtocolError::PeerIdMismatch)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        buf: Vec<u8>,
+    }
+
+    #[cfg(feature = "std")]
+    impl io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn write_block_in_one_call() {
+        let mut writer = CountingWriter::default();
+        let msg = Message::Block {
+            index: 666,
+            offset: 420,
+            data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+        };
+
+        msg.write_to(&mut writer).unwrap();
+
+        assert_eq!(writer.writes, 1);
+        assert_eq!(writer.buf.len(), 19);
+    }
+
+    #[test]
+    fn strict_handshake_has_no_reserved_bits() {
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+
+        let buf = handshake.serialize();
+
+        assert_eq!(&buf[20..28], &[0u8; 8]);
+    }
+
+    #[test]
+    fn negotiate_flags() {
+        let ours = HandshakeFlags::FAST | HandshakeFlags::EXTENDED;
+        let theirs = HandshakeFlags::FAST | HandshakeFlags::DHT;
+
+        let agreed = ours.negotiate(&theirs);
+
+        assert_eq!(agreed, HandshakeFlags::FAST);
+        assert!(agreed.supports_fast());
+        assert!(!agreed.supports_dht());
+        assert!(!agreed.supports_extended());
+        assert!(HandshakeFlags::all().supports_dht());
+        assert!(HandshakeFlags::all().supports_extended());
+    }
+
+    #[test]
+    fn build_extension_set() {
+        let flags = ExtensionSet::new()
+            .fast(true)
+            .dht(true)
+            .extended(true)
+            .build();
+        assert_eq!(flags, HandshakeFlags::all());
+
+        let flags = ExtensionSet::new().fast(true).dht(true).dht(false).build();
+        assert_eq!(flags, HandshakeFlags::FAST);
+        assert_eq!(ExtensionSet::default().build(), HandshakeFlags::empty());
+    }
+
+    #[test]
+    fn list_extensions() {
+        assert_eq!(
+            HandshakeFlags::all().extensions(),
+            vec![Extension::Dht, Extension::Fast, Extension::Extended]
+        );
+        assert_eq!(
+            (HandshakeFlags::EXTENDED | HandshakeFlags::DHT).extensions(),
+            vec![Extension::Dht, Extension::Extended]
+        );
+        assert!(HandshakeFlags::empty().extensions().is_empty());
+        for ext in Extension::ALL {
+            assert_eq!(ext.flag().extensions(), vec![ext]);
+        }
+    }
+
+    #[test]
+    fn flags_missing_from_peer() {
+        let ours = HandshakeFlags::FAST | HandshakeFlags::DHT | HandshakeFlags::EXTENDED;
+        let theirs = HandshakeFlags::DHT | HandshakeFlags::EXTENDED;
+
+        assert_eq!(ours.missing_from_peer(theirs), HandshakeFlags::FAST);
+        assert_eq!(theirs.missing_from_peer(ours), HandshakeFlags::empty());
+    }
+
+    #[test]
+    fn request_constructor() {
+        let msg = Message::request(666, 420, 16384).unwrap();
+
+        assert!(matches!(
+            msg,
+            Message::Request {
+                index: 666,
+                offset: 420,
+                length: 16384
+            }
+        ));
+    }
+
+    #[test]
+    fn request_constructor_overflow() {
+        let result = Message::request(666, u32::MAX - 1, 16384);
+
+        assert!(matches!(result, Err(RequestError::Overflow)));
+    }
+
+    #[test]
+    fn encode_to_slice_exact_fit() {
+        let mut buf = [0u8; 17];
+        let msg = Message::Request {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        };
+
+        let written = msg.encode_to_slice(&mut buf).unwrap();
+
+        assert_eq!(written, 17);
+        assert_eq!(&buf[..], &msg.serialize()[..]);
+    }
+
+    #[test]
+    fn encode_to_slice_leaves_rest_of_buffer() {
+        let mut buf = [0xFFu8; 8];
+
+        let written = Message::Unchoke.encode_to_slice(&mut buf).unwrap();
+
+        assert_eq!(written, 5);
+        assert_eq!(buf, [0x00, 0x00, 0x00, 0x01, 0x01, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn encode_to_slice_too_small() {
+        let mut buf = [0u8; 8];
+
+        let result = Message::Have(23).encode_to_slice(&mut buf);
+
+        assert_eq!(
+            result,
+            Err(EncodeError::BufferTooSmall {
+                needed: 9,
+                available: 8
+            })
+        );
+    }
+
+    fn round_trip(msg: Message) -> Message {
+        let mut buf = msg.serialize();
+        let parsed = Message::deserialize_with(&mut buf, HandshakeFlags::all())
+            .unwrap()
+            .unwrap();
+
+        assert!(buf.is_empty());
+
+        parsed
+    }
+
+    /// One of each message variant.
+    pub(crate) fn every_variant() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::Uninterested,
+            Message::Have(1),
+            Message::Bitfield(Bitfield::from_bytes(&[0b1010_0000], 8).unwrap()),
+            Message::Request {
+                index: 1,
+                offset: 2,
+                length: 3,
+            },
+            Message::Cancel {
+                index: 1,
+                offset: 2,
+                length: 3,
+            },
+            Message::Block {
+                index: 1,
+                offset: 2,
+                data: Bytes::from_static(b"data"),
+            },
+            Message::Port(6881),
+            Message::SuggestPiece { index: 1 },
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest {
+                index: 1,
+                offset: 2,
+                length: 3,
+            },
+            Message::AllowedFast { index: 1 },
+            Message::Extended {
+                ext_id: 1,
+                payload: Bytes::from_static(b"de"),
+            },
+            Message::Unknown {
+                id: 0x15,
+                payload: Bytes::from_static(b"future"),
+            },
+        ]
+    }
+
+    /// Every name `variant_name` can return.
+    const VARIANT_NAMES: &[&str] = &[
+        "KeepAlive",
+        "Choke",
+        "Unchoke",
+        "Interested",
+        "Uninterested",
+        "Have",
+        "Bitfield",
+        "Request",
+        "Cancel",
+        "Block",
+        "Port",
+        "SuggestPiece",
+        "HaveAll",
+        "HaveNone",
+        "RejectRequest",
+        "AllowedFast",
+        "Extended",
+        "Unknown",
+    ];
+
+    /// There's deliberately no wildcard arm here: a new variant won't compile
+    /// until it's named, and `every_variant_round_trips` then fails until
+    /// it's also added to `every_variant`.
+    fn variant_name(msg: &Message) -> &'static str {
+        match msg {
+            Message::KeepAlive => "KeepAlive",
+            Message::Choke => "Choke",
+            Message::Unchoke => "Unchoke",
+            Message::Interested => "Interested",
+            Message::Uninterested => "Uninterested",
+            Message::Have(_) => "Have",
+            Message::Bitfield(_) => "Bitfield",
+            Message::Request { .. } => "Request",
+            Message::Cancel { .. } => "Cancel",
+            Message::Block { .. } => "Block",
+            Message::Port(_) => "Port",
+            Message::SuggestPiece { .. } => "SuggestPiece",
+            Message::HaveAll => "HaveAll",
+            Message::HaveNone => "HaveNone",
+            Message::RejectRequest { .. } => "RejectRequest",
+            Message::AllowedFast { .. } => "AllowedFast",
+            Message::Extended { .. } => "Extended",
+            Message::Unknown { .. } => "Unknown",
+        }
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let names: HashSet<&str> = every_variant().iter().map(variant_name).collect();
+        let expected: HashSet<&str> = VARIANT_NAMES.iter().copied().collect();
+        assert_eq!(names, expected);
+
+        for msg in every_variant() {
+            assert_eq!(round_trip(msg.clone()), msg);
+        }
+    }
+
+    #[test]
+    fn every_variant_encodes_consistently() {
+        for msg in every_variant() {
+            let mut buf = [0; 64];
+            let len = msg.encode_to_slice(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &msg.serialize()[..]);
+        }
+    }
+
+    #[test]
+    fn message_id_matches_wire_byte() {
+        for msg in every_variant() {
+            let buf = msg.clone().serialize();
+            match msg.id() {
+                Some(id) => {
+                    assert_eq!(buf[4], id as u8);
+                    assert_eq!(MessageId::from_u8(buf[4]), Some(id));
+                }
+                None => assert!(matches!(msg, Message::KeepAlive | Message::Unknown { .. })),
+            }
+        }
+    }
+
+    #[test]
+    fn message_id_from_u8() {
+        assert_eq!(MessageId::from_u8(0x00), Some(MessageId::Choke));
+        assert_eq!(MessageId::from_u8(0x08), Some(MessageId::Cancel));
+        assert_eq!(MessageId::from_u8(0x09), Some(MessageId::Port));
+        assert_eq!(MessageId::from_u8(0x0A), None);
+        assert_eq!(MessageId::from_u8(0xFF), None);
+    }
+
+    #[test]
+    fn encoded_len_matches_serialize() {
+        for msg in every_variant() {
+            assert_eq!(msg.encoded_len(), msg.clone().serialize().len());
+        }
+    }
+
+    #[test]
+    fn encoded_len_examples() {
+        assert_eq!(Message::KeepAlive.encoded_len(), 4);
+        assert_eq!(Message::Choke.encoded_len(), 5);
+        assert_eq!(
+            Message::Block {
+                index: 0,
+                offset: 0,
+                data: Bytes::from_static(&[0; 100]),
+            }
+            .encoded_len(),
+            113
+        );
+    }
+
+    #[test]
+    fn serialize_into_appends() {
+        let mut buf = BytesMut::from(&b"head"[..]);
+        for msg in every_variant() {
+            msg.serialize_into(&mut buf);
+        }
+
+        let mut expected = b"head".to_vec();
+        for msg in every_variant() {
+            expected.extend_from_slice(&msg.serialize());
+        }
+        assert_eq!(&buf[..], &expected[..]);
+    }
+
+    #[test]
+    fn deserialize_port_zero() {
+        let mut buf = Bytes::from_static(&[0x00, 0x00, 0x00, 0x03, 0x09, 0x00, 0x00]);
+
+        assert_eq!(
+            Message::deserialize_with(&mut buf, HandshakeFlags::DHT),
+            Err(ProtocolError::InvalidPort)
+        );
+    }
+
+    #[test]
+    fn display_messages() {
+        let mut bitfield = Bitfield::new(24);
+        for index in 0..18 {
+            bitfield.set(index).unwrap();
+        }
+
+        assert_eq!(
+            Message::Request {
+                index: 666,
+                offset: 420,
+                length: 16384
+            }
+            .to_string(),
+            "Request(piece=666 off=420 len=16384)"
+        );
+        assert_eq!(
+            Message::Bitfield(bitfield).to_string(),
+            "Bitfield(24 pieces, 18 set)"
+        );
+        assert_eq!(
+            Message::Block {
+                index: 666,
+                offset: 420,
+                data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+            }
+            .to_string(),
+            "Block(piece=666 off=420 6 bytes)"
+        );
+        assert_eq!(Message::KeepAlive.to_string(), "KeepAlive");
+    }
+
+    #[test]
+    fn display_handshake() {
+        let handshake = Handshake::strict([0xAB; 20], *b"-LS0001-abcdefghijkl");
+
+        assert_eq!(
+            handshake.to_string(),
+            "Handshake(info_hash=abababababababababababababababababababab \
+             peer_id=2d4c53303030312d6162636465666768696a6b6c)"
+        );
+    }
+
+    #[test]
+    fn deserialize_keepalive() {
+        assert_eq!(round_trip(Message::KeepAlive), Message::KeepAlive);
+    }
+
+    #[test]
+    fn deserialize_choke() {
+        assert_eq!(round_trip(Message::Choke), Message::Choke);
+    }
+
+    #[test]
+    fn deserialize_unchoke() {
+        assert_eq!(round_trip(Message::Unchoke), Message::Unchoke);
+    }
+
+    #[test]
+    fn deserialize_interested() {
+        assert_eq!(round_trip(Message::Interested), Message::Interested);
+    }
+
+    #[test]
+    fn deserialize_uninterested() {
+        assert_eq!(round_trip(Message::Uninterested), Message::Uninterested);
+    }
+
+    #[test]
+    fn deserialize_have() {
+        assert_eq!(round_trip(Message::Have(23)), Message::Have(23));
+    }
+
+    #[test]
+    fn deserialize_bitfield() {
+        let bitfield = Bitfield::from_bytes(&[0xFF, 0xFF, 0xFF], 24).unwrap();
+
+        assert_eq!(
+            round_trip(Message::Bitfield(bitfield.clone())),
+            Message::Bitfield(bitfield)
+        );
+    }
+
+    #[test]
+    fn deserialize_request() {
+        let msg = Message::Request {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_cancel() {
+        let msg = Message::Cancel {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_block() {
+        let msg = Message::Block {
+            index: 666,
+            offset: 420,
+            data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+        };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_partial_frame() {
+        let full = Message::Have(23).serialize();
+
+        for len in 0..full.len() {
+            let mut buf = full.slice(..len);
+
+            assert_eq!(Message::deserialize(&mut buf), Ok(None));
+            assert_eq!(buf.len(), len);
+        }
+    }
+
+    #[test]
+    fn deserialize_resumes_after_last_byte() {
+        for msg in every_variant() {
+            let full = msg.clone().serialize();
+            let (head, last) = full.split_at(full.len() - 1);
+
+            let mut buf = Bytes::copy_from_slice(head);
+            assert_eq!(
+                Message::deserialize_with(&mut buf, HandshakeFlags::all()),
+                Ok(None)
+            );
+            assert_eq!(&buf[..], head);
+
+            let mut buf = BytesMut::from(&buf[..]);
+            buf.extend_from_slice(last);
+            let mut buf = buf.freeze();
+            assert_eq!(
+                Message::deserialize_with(&mut buf, HandshakeFlags::all()),
+                Ok(Some(msg))
+            );
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn deserialize_several_frames() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&Message::Unchoke.serialize());
+        buf.extend_from_slice(&Message::Have(23).serialize());
+        buf.extend_from_slice(&Message::KeepAlive.serialize());
+        let mut buf = buf.freeze();
+
+        assert_eq!(Message::deserialize(&mut buf), Ok(Some(Message::Unchoke)));
+        assert_eq!(Message::deserialize(&mut buf), Ok(Some(Message::Have(23))));
+        assert_eq!(Message::deserialize(&mut buf), Ok(Some(Message::KeepAlive)));
+        assert_eq!(Message::deserialize(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn deserialize_unknown_id() {
+        let mut buf = Bytes::from(vec![0x00, 0x00, 0x00, 0x01, 0x63]);
+
+        assert_eq!(
+            Message::deserialize(&mut buf),
+            Ok(Some(Message::Unknown {
+                id: 0x63,
+                payload: Bytes::new(),
+            }))
+        );
+    }
+
+    #[test]
+    fn unknown_message_keeps_stream_in_sync() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x04, 0x63, 0xDE, 0xAD, 0x00]);
+        Message::Have(23).serialize_into(&mut buf);
+        let wire = buf.clone().freeze();
+        let mut buf = buf.freeze();
+
+        let unknown = Message::deserialize(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            unknown,
+            Message::Unknown {
+                id: 0x63,
+                payload: Bytes::from_static(&[0xDE, 0xAD, 0x00]),
+            }
+        );
+        assert_eq!(unknown.id(), None);
+        assert_eq!(Message::deserialize(&mut buf), Ok(Some(Message::Have(23))));
+        assert_eq!(Message::deserialize(&mut buf), Ok(None));
+
+        assert_eq!(unknown.serialize(), wire.slice(..8));
+    }
+
+    #[test]
+    fn unknown_message_waits_for_whole_frame() {
+        let mut buf = Bytes::from(vec![0x00, 0x00, 0x00, 0x04, 0x63, 0xDE]);
+
+        assert_eq!(Message::deserialize(&mut buf), Ok(None));
+        assert_eq!(buf.len(), 6);
+    }
+
+    #[test]
+    fn unknown_message_in_unmodelled_gap() {
+        for id in [0x0A, 0x0C, 0x12, 0x13] {
+            let msg = Message::Unknown {
+                id,
+                payload: Bytes::from_static(b"gap"),
+            };
+            assert_eq!(round_trip(msg.clone()), msg);
+        }
+    }
+
+    #[test]
+    fn unknown_message_with_modelled_id_is_refused() {
+        let msg = Message::Unknown {
+            id: 0x04,
+            payload: Bytes::from_static(&[0, 0, 0, 1]),
+        };
+
+        assert_eq!(
+            msg.clone().try_serialize(),
+            Err(EncodeError::ModelledId(0x04))
+        );
+        let mut buf = BytesMut::new();
+        assert_eq!(
+            msg.clone().try_serialize_into(&mut buf),
+            Err(EncodeError::ModelledId(0x04))
+        );
+        assert!(buf.is_empty());
+        assert_eq!(
+            msg.encode_to_slice(&mut [0; 16]),
+            Err(EncodeError::ModelledId(0x04))
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn serialize_unknown_message_with_modelled_id() {
+        Message::Unknown {
+            id: 0x14,
+            payload: Bytes::new(),
+        }
+        .serialize();
+    }
+
+    #[test]
+    fn default_max_frame_len_allows_extended_messages() {
+        assert_eq!(
+            default_max_frame_len(HandshakeFlags::FAST),
+            DEFAULT_MAX_FRAME_LEN
+        );
+        assert_eq!(
+            default_max_frame_len(HandshakeFlags::EXTENDED),
+            DEFAULT_MAX_EXTENDED_FRAME_LEN
+        );
+    }
+
+    #[test]
+    fn unknown_message_length_is_capped() {
+        let mut header = MAX_UNKNOWN_MESSAGE_LEN.to_be_bytes().to_vec();
+        header.push(0x63);
+        assert_eq!(frame_len(&header, HandshakeFlags::empty()), Ok(None));
+
+        let mut header = (MAX_UNKNOWN_MESSAGE_LEN + 1).to_be_bytes().to_vec();
+        header.push(0x63);
+        assert_eq!(
+            frame_len(&header, HandshakeFlags::empty()),
+            Err(ProtocolError::PayloadTooLarge)
+        );
+        assert_eq!(
+            MessageRef::parse(&[0xFF, 0xFF, 0xFF, 0xFF, 0x63]),
+            Err(ProtocolError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn deserialize_length_mismatch() {
+        let mut buf = Bytes::from(vec![0x00, 0x00, 0x00, 0x02, 0x04, 0x00]);
+
+        assert_eq!(
+            Message::deserialize(&mut buf),
+            Err(ProtocolError::LengthMismatch {
+                declared: 2,
+                actual: 5
+            })
+        );
+    }
+
+    #[test]
+    fn deserialize_short_block() {
+        let mut buf = Bytes::from(vec![0x00, 0x00, 0x00, 0x05, 0x07, 0x00, 0x00, 0x00, 0x01]);
+
+        assert_eq!(
+            Message::deserialize(&mut buf),
+            Err(ProtocolError::LengthMismatch {
+                declared: 5,
+                actual: 9
+            })
+        );
+    }
+
+    #[test]
+    fn deserialize_oversized_bitfield() {
+        let mut buf = Bytes::from(vec![0x7F, 0xFF, 0xFF, 0xFF, 0x05]);
+
+        assert_eq!(
+            Message::deserialize(&mut buf),
+            Err(ProtocolError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn largest_bitfield_header_is_accepted() {
+        let len = 1 + Bitfield::byte_len(u32::MAX) as u32;
+        let mut buf = len.to_be_bytes().to_vec();
+        buf.push(0x05);
+
+        assert_eq!(frame_len(&buf, HandshakeFlags::empty()), Ok(None));
+
+        let mut buf = (len + 1).to_be_bytes().to_vec();
+        buf.push(0x05);
+
+        assert_eq!(
+            frame_len(&buf, HandshakeFlags::empty()),
+            Err(ProtocolError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn largest_block_header_is_accepted() {
+        let mut buf = u32::MAX.to_be_bytes().to_vec();
+        buf.push(0x07);
+
+        assert_eq!(frame_len(&buf, HandshakeFlags::empty()), Ok(None));
+    }
+
+    #[test]
+    fn round_trip_max_piece_index() {
+        let messages = [
+            Message::Have(u32::MAX),
+            Message::request(u32::MAX, 0, DEFAULT_MAX_BLOCK_SIZE).unwrap(),
+            Message::request(
+                u32::MAX,
+                u32::MAX - DEFAULT_MAX_BLOCK_SIZE,
+                DEFAULT_MAX_BLOCK_SIZE,
+            )
+            .unwrap(),
+            Message::Cancel {
+                index: u32::MAX,
+                offset: u32::MAX,
+                length: 1,
+            },
+            Message::Block {
+                index: u32::MAX,
+                offset: u32::MAX,
+                data: Bytes::from_static(b"x"),
+            },
+            Message::SuggestPiece { index: u32::MAX },
+            Message::AllowedFast { index: u32::MAX },
+        ];
+
+        for msg in messages {
+            assert_eq!(round_trip(msg.clone()), msg);
+        }
+    }
+
+    #[test]
+    fn deserialize_handshake() {
+        let mut buf = vec![19];
+        buf.extend_from_slice(b"BitTorrent protocol");
+        buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01]);
+        buf.extend_from_slice(b"12345678901234567890");
+        buf.extend_from_slice(b"Landslide Experiment");
+
+        let handshake = Handshake::deserialize(&buf).unwrap();
+
+        assert_eq!(
+            handshake.flags(),
+            HandshakeFlags::FAST | HandshakeFlags::DHT
+        );
+        assert_eq!(&handshake.info_hash, b"12345678901234567890");
+        assert_eq!(&handshake.peer_id, b"Landslide Experiment");
+    }
+
+    #[test]
+    fn handshake_serialize_into_appends() {
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+        let mut buf = BytesMut::from(&b"head"[..]);
+        handshake.serialize_into(&mut buf);
+
+        assert_eq!(&buf[..4], b"head");
+        assert_eq!(&buf[4..], &handshake.serialize()[..]);
+    }
+
+    #[test]
+    fn round_trip_handshake() {
+        let handshake = Handshake {
+            reserved: (HandshakeFlags::FAST | HandshakeFlags::EXTENDED).bits(),
+            peer_id: *b"Landslide Experiment",
+            info_hash: *b"12345678901234567890",
+        };
+
+        let buf = handshake.serialize();
+
+        assert_eq!(Handshake::deserialize(&buf), Ok(handshake));
+    }
+
+    #[test]
+    fn deserialize_handshake_truncated() {
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+        let buf = handshake.serialize();
+
+        assert_eq!(
+            Handshake::deserialize(&buf[..67]),
+            Err(ProtocolError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn deserialize_handshake_wrong_protocol() {
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+        let mut buf = handshake.serialize().to_vec();
+        buf[1..20].copy_from_slice(b"BitTorrent Protocol");
+
+        assert_eq!(
+            Handshake::deserialize(&buf),
+            Err(ProtocolError::InvalidProtocolString)
+        );
+    }
+
+    #[test]
+    fn parse_compact_peer_list() {
+        let data = [127, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0xC8, 0xD5];
+
+        let peers = parse_compact_peers(&data).unwrap();
+
+        assert_eq!(
+            peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:51413".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_truncated_compact_peer_list() {
+        let data = [127, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0xC8];
+
+        assert_eq!(
+            parse_compact_peers(&data),
+            Err(ProtocolError::InvalidCompactPeers(11))
+        );
+    }
+
+    #[test]
+    fn new_handshake() {
+        let handshake = Handshake::new(
+            *b"12345678901234567890",
+            *b"Landslide Experiment",
+            HandshakeFlags::EXTENDED,
+        );
+
+        assert_eq!(handshake.info_hash(), b"12345678901234567890");
+        assert_eq!(handshake.peer_id(), b"Landslide Experiment");
+        assert_eq!(handshake.flags(), HandshakeFlags::EXTENDED);
+    }
+
+    #[test]
+    fn handshake_preserves_unknown_reserved_bits() {
+        let mut buf = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment")
+            .serialize()
+            .to_vec();
+        // Azureus Messaging Protocol, which we have no flag for, plus DHT.
+        buf[20..28].copy_from_slice(&[0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+
+        let handshake = Handshake::deserialize(&buf).unwrap();
+
+        assert_eq!(handshake.flags(), HandshakeFlags::DHT);
+        assert_eq!(handshake.raw_reserved(), 0x8000_0000_0000_0001);
+        assert_eq!(&handshake.serialize()[..], &buf[..]);
+    }
+
+    #[test]
+    fn try_serialize_block() {
+        let msg = Message::Block {
+            index: 666,
+            offset: 420,
+            data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+        };
+
+        assert_eq!(msg.try_serialize().unwrap().len(), 19);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn length_prefix_overflow() {
+        assert_eq!(length_prefix(u32::MAX as usize), Ok(u32::MAX));
+        assert_eq!(
+            length_prefix(u32::MAX as usize + 1),
+            Err(EncodeError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn deserialize_suggest_piece() {
+        let msg = Message::SuggestPiece { index: 23 };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_have_all() {
+        assert_eq!(round_trip(Message::HaveAll), Message::HaveAll);
+    }
+
+    #[test]
+    fn deserialize_have_none() {
+        assert_eq!(round_trip(Message::HaveNone), Message::HaveNone);
+    }
+
+    #[test]
+    fn deserialize_reject_request() {
+        let msg = Message::RejectRequest {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_allowed_fast() {
+        let msg = Message::AllowedFast { index: 23 };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_fast_message_without_fast() {
+        let mut buf = Message::HaveAll.serialize();
+
+        assert_eq!(
+            Message::deserialize_with(&mut buf, HandshakeFlags::DHT | HandshakeFlags::EXTENDED),
+            Err(ProtocolError::ExtensionNotNegotiated(0x0E))
+        );
+        assert_eq!(
+            Message::deserialize(&mut buf),
+            Err(ProtocolError::ExtensionNotNegotiated(0x0E))
+        );
+    }
+
+    #[test]
+    fn deserialize_fast_message_with_fast() {
+        let mut buf = Message::HaveNone.serialize();
+
+        assert_eq!(
+            Message::deserialize_with(&mut buf, HandshakeFlags::FAST),
+            Ok(Some(Message::HaveNone))
+        );
+    }
+
+    #[test]
+    fn match_v2_info_hash() {
+        // SHA-256 of the empty string
+        let full: [u8; 32] = [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+            0x78, 0x52, 0xb8, 0x55,
+        ];
+        let truncated: [u8; 20] = [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4,
+        ];
+
+        let handshake = Handshake::strict(truncated, *b"Landslide Experiment");
+        assert!(handshake.matches_v2_info_hash(&full));
+
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+        assert!(!handshake.matches_v2_info_hash(&full));
+    }
+
+    #[test]
+    fn serialize_port() {
+        let buf = Message::Port(6881).serialize();
+
+        assert_eq!(&buf[..], &[0x00, 0x00, 0x00, 0x03, 0x09, 0x1A, 0xE1]);
+    }
+
+    #[test]
+    fn deserialize_port() {
+        assert_eq!(round_trip(Message::Port(6881)), Message::Port(6881));
+    }
+
+    #[test]
+    fn deserialize_port_without_dht() {
+        let mut buf = Message::Port(6881).serialize();
+
+        assert_eq!(
+            Message::deserialize_with(&mut buf, HandshakeFlags::FAST),
+            Err(ProtocolError::ExtensionNotNegotiated(0x09))
+        );
+    }
+
+    #[test]
+    fn serialize_extended() {
+        let msg = Message::Extended {
+            ext_id: 3,
+            payload: Bytes::from_static(b"de"),
+        };
+        let buf = msg.serialize();
+
+        assert_eq!(&buf[..], &[0x00, 0x00, 0x00, 0x04, 0x14, 0x03, b'd', b'e']);
+    }
+
+    #[test]
+    fn deserialize_extended() {
+        let msg = Message::Extended {
+            ext_id: 3,
+            payload: Bytes::from_static(b"d8:msg_typei0e5:piecei0ee"),
+        };
+
+        assert_eq!(round_trip(msg.clone()), msg);
+    }
+
+    #[test]
+    fn deserialize_extended_without_extended() {
+        let mut buf = Message::Extended {
+            ext_id: 3,
+            payload: Bytes::from_static(b"de"),
+        }
+        .serialize();
+
+        assert_eq!(
+            Message::deserialize_with(&mut buf, HandshakeFlags::FAST | HandshakeFlags::DHT),
+            Err(ProtocolError::ExtensionNotNegotiated(0x14))
+        );
+    }
 }
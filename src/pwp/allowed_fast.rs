@@ -0,0 +1,80 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Choosing which pieces a peer may request while choked (BEP 6).
+
+use crate::{InfoHash, PieceIndex};
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
+
+/// The `k` pieces a peer at `ip` may request while choked, in the order BEP 6 picks them.
+///
+/// Every client computes the same set for a given peer, so it can be sent as `AllowedFast` messages.
+/// Asking for more pieces than the torrent has returns every piece.
+pub fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &InfoHash,
+    num_pieces: u32,
+    k: usize,
+) -> Vec<PieceIndex> {
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+
+    // Peers on the same /24 get the same set, so they can't collect extra pieces.
+    let mut x = (u32::from(ip) & 0xFFFF_FF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == k {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::allowed_fast_set;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn bep_6_example() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+
+        assert_eq!(
+            allowed_fast_set(ip, &[0xAA; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &[0xAA; 20], 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn same_subnet_same_set() {
+        let a = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 200), &[0xAA; 20], 1313, 7);
+        let b = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &[0xAA; 20], 1313, 7);
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn more_than_every_piece() {
+        let mut set = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 200), &[0xAA; 20], 3, 10);
+        set.sort_unstable();
+
+        assert_eq!(set, vec![0, 1, 2]);
+        assert!(allowed_fast_set(Ipv4Addr::LOCALHOST, &[0; 20], 0, 10).is_empty());
+    }
+}
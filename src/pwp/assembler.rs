@@ -0,0 +1,260 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Stitching received blocks back together into whole pieces.
+
+use crate::{BlockOffset, PieceIndex};
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+/// Errors from adding a block to a `PieceAssembler`.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AssemblyError {
+    #[error("piece {0} is past the end of the torrent")]
+    PieceOutOfRange(PieceIndex),
+    #[error("block at offset {offset} of {length} bytes runs past the end of piece {index}")]
+    BlockOutOfBounds {
+        index: PieceIndex,
+        offset: BlockOffset,
+        length: usize,
+    },
+    #[error("block at offset {offset} of piece {index} overlaps one we already have")]
+    OverlappingBlock {
+        index: PieceIndex,
+        offset: BlockOffset,
+    },
+    #[error("block is empty")]
+    EmptyBlock,
+    #[error("piece {0} doesn't match its hash")]
+    HashMismatch(PieceIndex),
+}
+
+/// Collects the blocks of each piece as they arrive, handing back a piece once all of it is here.
+///
+/// Finished pieces are only checked against their hashes if they were given with `with_piece_hashes`.
+#[derive(Debug)]
+pub struct PieceAssembler {
+    piece_length: u32,
+    total_length: u64,
+    pieces: HashMap<PieceIndex, PartialPiece>,
+    #[cfg(feature = "sha1")]
+    piece_hashes: Option<Vec<[u8; 20]>>,
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    data: BytesMut,
+    /// The byte ranges received so far, as start offset to end offset.
+    received: BTreeMap<BlockOffset, BlockOffset>,
+    received_len: usize,
+}
+
+impl PieceAssembler {
+    /// An assembler for a torrent of `total_length` bytes split into pieces of `piece_length` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `piece_length` is zero.
+    pub fn new(piece_length: u32, total_length: u64) -> Self {
+        assert!(piece_length > 0, "piece length must be positive");
+        PieceAssembler {
+            piece_length,
+            total_length,
+            pieces: HashMap::new(),
+            #[cfg(feature = "sha1")]
+            piece_hashes: None,
+        }
+    }
+
+    /// Check each finished piece against its SHA-1 hash from the info dictionary.
+    ///
+    /// A piece that doesn't match is thrown away, and will need to be downloaded again.
+    #[cfg(feature = "sha1")]
+    pub fn with_piece_hashes(mut self, hashes: Vec<[u8; 20]>) -> Self {
+        self.piece_hashes = Some(hashes);
+        self
+    }
+
+    /// The length of the given piece, which is shorter than the others if it's the last one.
+    pub fn piece_len(&self, index: PieceIndex) -> Option<u32> {
+        let start = index as u64 * self.piece_length as u64;
+        if start >= self.total_length {
+            return None;
+        }
+        Some((self.total_length - start).min(self.piece_length as u64) as u32)
+    }
+
+    /// The number of pieces with some, but not all, of their blocks received.
+    pub fn pending_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Store a block, returning its piece if this block completed it.
+    pub fn add_block(
+        &mut self,
+        index: PieceIndex,
+        offset: BlockOffset,
+        data: &[u8],
+    ) -> Result<Option<Bytes>, AssemblyError> {
+        let piece_len = self
+            .piece_len(index)
+            .ok_or(AssemblyError::PieceOutOfRange(index))?;
+        if data.is_empty() {
+            return Err(AssemblyError::EmptyBlock);
+        }
+        let end = offset as u64 + data.len() as u64;
+        if end > piece_len as u64 {
+            return Err(AssemblyError::BlockOutOfBounds {
+                index,
+                offset,
+                length: data.len(),
+            });
+        }
+        let end = end as BlockOffset;
+
+        let piece = self.pieces.entry(index).or_insert_with(|| PartialPiece {
+            data: BytesMut::zeroed(piece_len as usize),
+            received: BTreeMap::new(),
+            received_len: 0,
+        });
+
+        let overlaps_before = piece
+            .received
+            .range(..=offset)
+            .next_back()
+            .is_some_and(|(_, prev_end)| *prev_end > offset);
+        let overlaps_after = piece
+            .received
+            .range(offset..)
+            .next()
+            .is_some_and(|(next_start, _)| *next_start < end);
+        if overlaps_before || overlaps_after {
+            return Err(AssemblyError::OverlappingBlock { index, offset });
+        }
+
+        piece.data[offset as usize..end as usize].copy_from_slice(data);
+        piece.received.insert(offset, end);
+        piece.received_len += data.len();
+
+        if piece.received_len < piece_len as usize {
+            return Ok(None);
+        }
+
+        let piece = self.pieces.remove(&index).expect("piece was just updated");
+        #[cfg(feature = "sha1")]
+        if !self.matches_hash(index, &piece.data) {
+            return Err(AssemblyError::HashMismatch(index));
+        }
+        Ok(Some(piece.data.freeze()))
+    }
+
+    #[cfg(feature = "sha1")]
+    fn matches_hash(&self, index: PieceIndex, data: &[u8]) -> bool {
+        match &self.piece_hashes {
+            Some(hashes) => hashes
+                .get(index as usize)
+                .is_some_and(|expected| crate::piece::verify_piece(data, expected)),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::AssemblyError;
+    use crate::pwp::PieceAssembler;
+
+    #[test]
+    fn assemble_out_of_order() {
+        let mut assembler = PieceAssembler::new(8, 20);
+
+        assert_eq!(assembler.add_block(1, 4, b"5678"), Ok(None));
+        assert_eq!(
+            assembler.add_block(1, 0, b"1234"),
+            Ok(Some("12345678".into()))
+        );
+        assert_eq!(assembler.pending_pieces(), 0);
+    }
+
+    #[test]
+    fn assemble_short_last_piece() {
+        let mut assembler = PieceAssembler::new(8, 20);
+
+        assert_eq!(assembler.piece_len(2), Some(4));
+        assert_eq!(assembler.add_block(2, 0, b"abcd"), Ok(Some("abcd".into())));
+    }
+
+    #[test]
+    fn reject_out_of_bounds() {
+        let mut assembler = PieceAssembler::new(8, 20);
+
+        assert_eq!(
+            assembler.add_block(3, 0, b"a"),
+            Err(AssemblyError::PieceOutOfRange(3))
+        );
+        assert_eq!(
+            assembler.add_block(2, 2, b"abc"),
+            Err(AssemblyError::BlockOutOfBounds {
+                index: 2,
+                offset: 2,
+                length: 3
+            })
+        );
+        assert_eq!(
+            assembler.add_block(0, u32::MAX, b"a"),
+            Err(AssemblyError::BlockOutOfBounds {
+                index: 0,
+                offset: u32::MAX,
+                length: 1
+            })
+        );
+        assert_eq!(
+            assembler.add_block(0, 0, b""),
+            Err(AssemblyError::EmptyBlock)
+        );
+    }
+
+    #[test]
+    fn reject_overlaps() {
+        let mut assembler = PieceAssembler::new(16, 64);
+        assembler.add_block(0, 4, b"abcd").unwrap();
+
+        for (offset, data) in [(4, &b"ab"[..]), (2, b"abc"), (7, b"ab"), (0, b"abcdefgh")] {
+            assert_eq!(
+                assembler.add_block(0, offset, data),
+                Err(AssemblyError::OverlappingBlock { index: 0, offset })
+            );
+        }
+
+        assert_eq!(assembler.add_block(0, 0, b"wxyz"), Ok(None));
+        assert_eq!(
+            assembler.add_block(0, 8, b"12345678"),
+            Ok(Some("wxyzabcd12345678".into()))
+        );
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn reject_corrupt_piece() {
+        use sha1::{Digest, Sha1};
+
+        let hashes = vec![Sha1::digest(b"12345678").into(), [0; 20]];
+        let mut assembler = PieceAssembler::new(8, 12).with_piece_hashes(hashes);
+
+        assert_eq!(
+            assembler.add_block(0, 0, b"12345678"),
+            Ok(Some("12345678".into()))
+        );
+        assert_eq!(
+            assembler.add_block(1, 0, b"9abc"),
+            Err(AssemblyError::HashMismatch(1))
+        );
+        assert_eq!(assembler.pending_pieces(), 0);
+        assert_eq!(
+            assembler.add_block(1, 0, b"9abc"),
+            Err(AssemblyError::HashMismatch(1))
+        );
+    }
+}
@@ -0,0 +1,534 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! The set of pieces a peer has, as sent in a `Bitfield` message.
+
+use crate::pwp::{have_messages, Message, ProtocolError};
+use crate::PieceIndex;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "serde")]
+use core::convert::TryFrom;
+use thiserror::Error;
+
+/// Which pieces of a torrent a peer has.
+///
+/// Piece 0 is the high bit of the first byte.
+/// Any bits past the last piece are spare and must be zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawBitfield"))]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    piece_count: u32,
+}
+
+/// Errors from building or updating a `Bitfield`.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BitfieldError {
+    #[error("piece {index} is out of range for a torrent with {piece_count} pieces")]
+    IndexOutOfRange { index: PieceIndex, piece_count: u32 },
+    #[error("bitfield should be {expected} bytes long but is only {actual}")]
+    TooShort { expected: usize, actual: usize },
+    #[error("bitfield should be {expected} bytes long but is {actual}")]
+    TooLong { expected: usize, actual: usize },
+    #[error("bitfield has spare bits set after the last piece")]
+    SpareBitsSet,
+}
+
+impl Bitfield {
+    /// An empty bitfield for a torrent with `piece_count` pieces.
+    pub fn new(piece_count: u32) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; Bitfield::byte_len(piece_count)],
+            piece_count,
+        }
+    }
+
+    /// A bitfield for a torrent with `piece_count` pieces, with every piece present.
+    pub fn full(piece_count: u32) -> Bitfield {
+        let mut bytes = vec![0xFF; Bitfield::byte_len(piece_count)];
+        if let Some(last) = bytes.last_mut() {
+            *last &= 0xFF << ((8 - piece_count % 8) % 8);
+        }
+        Bitfield { bytes, piece_count }
+    }
+
+    /// Build a bitfield from its wire representation, checking it against the torrent's piece count.
+    pub fn from_bytes(bytes: &[u8], piece_count: u32) -> Result<Bitfield, BitfieldError> {
+        BitfieldRef::new(bytes, piece_count).map(Bitfield::from)
+    }
+
+    /// Collect pieces announced one at a time, like through `Have` messages, into a bitfield.
+    pub fn from_haves(
+        indices: impl IntoIterator<Item = PieceIndex>,
+        piece_count: u32,
+    ) -> Result<Bitfield, BitfieldError> {
+        let mut bitfield = Bitfield::new(piece_count);
+        for index in indices {
+            bitfield.set(index)?;
+        }
+        Ok(bitfield)
+    }
+
+    /// Check a bitfield's wire representation is exactly right for `piece_count` pieces:
+    /// not too short, not too long, and with no spare bits set after the last piece.
+    pub fn validate_len(bytes: &[u8], piece_count: u32) -> Result<(), BitfieldError> {
+        let expected = Bitfield::byte_len(piece_count);
+        let actual = bytes.len();
+        if actual < expected {
+            return Err(BitfieldError::TooShort { expected, actual });
+        }
+        if actual > expected {
+            return Err(BitfieldError::TooLong { expected, actual });
+        }
+        if (BitfieldRef { bytes, piece_count }).spare_bits() != 0 {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        Ok(())
+    }
+
+    /// Check a bitfield from a peer fits a torrent with `num_pieces` pieces.
+    ///
+    /// A peer whose bitfield fails this should be dropped.
+    /// To get one that's sized to the torrent, use [`Bitfield::from_bytes`] instead.
+    pub fn validate(&self, num_pieces: u32) -> Result<(), ProtocolError> {
+        Ok(Bitfield::validate_len(&self.bytes, num_pieces)?)
+    }
+
+    /// The number of bytes needed to hold a bit for each of `piece_count` pieces.
+    pub fn byte_len(piece_count: u32) -> usize {
+        (piece_count as u64).div_ceil(8) as usize
+    }
+
+    /// The number of pieces this bitfield covers.
+    pub fn piece_count(&self) -> u32 {
+        self.piece_count
+    }
+
+    /// The bitfield as it is sent on the wire.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Borrow this bitfield as a read-only view.
+    pub fn view(&self) -> BitfieldRef<'_> {
+        BitfieldRef {
+            bytes: &self.bytes,
+            piece_count: self.piece_count,
+        }
+    }
+
+    /// Whether the piece is present. Pieces out of range are never present.
+    pub fn get(&self, index: PieceIndex) -> bool {
+        self.view().has_piece(index)
+    }
+
+    /// Mark a piece as present.
+    pub fn set(&mut self, index: PieceIndex) -> Result<(), BitfieldError> {
+        self.check_index(index)?;
+        self.bytes[index as usize / 8] |= mask(index);
+        Ok(())
+    }
+
+    /// Mark a piece as missing.
+    pub fn clear(&mut self, index: PieceIndex) -> Result<(), BitfieldError> {
+        self.check_index(index)?;
+        self.bytes[index as usize / 8] &= !mask(index);
+        Ok(())
+    }
+
+    /// The number of pieces present.
+    pub fn count_ones(&self) -> u64 {
+        self.view().count_set()
+    }
+
+    /// The indices of the pieces present, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = PieceIndex> + '_ {
+        self.view().iter_set_pieces()
+    }
+
+    /// One `Have` message for each piece present, in ascending order.
+    pub fn haves(&self) -> impl Iterator<Item = Message> + '_ {
+        have_messages(self.iter())
+    }
+
+    /// Whether every piece is present.
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == u64::from(self.piece_count)
+    }
+
+    fn check_index(&self, index: PieceIndex) -> Result<(), BitfieldError> {
+        if index < self.piece_count {
+            Ok(())
+        } else {
+            Err(BitfieldError::IndexOutOfRange {
+                index,
+                piece_count: self.piece_count,
+            })
+        }
+    }
+}
+
+/// A read-only bitfield borrowed from bytes owned elsewhere, like a message payload.
+///
+/// Use this to read a peer's bitfield without copying it, and convert it into a [`Bitfield`] to keep it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitfieldRef<'a> {
+    bytes: &'a [u8],
+    piece_count: u32,
+}
+
+impl<'a> BitfieldRef<'a> {
+    /// A bitfield straight off the wire, before we know how many pieces it's meant to cover.
+    ///
+    /// Every bit is treated as a piece, so none of them are spare.
+    pub(crate) fn from_payload(bytes: &'a [u8]) -> Self {
+        let piece_count = (bytes.len() as u64 * 8).min(u32::MAX as u64) as u32;
+
+        BitfieldRef { bytes, piece_count }
+    }
+
+    /// View a bitfield's wire representation, checking it against the torrent's piece count.
+    pub fn new(bytes: &'a [u8], piece_count: u32) -> Result<Self, BitfieldError> {
+        Bitfield::validate_len(bytes, piece_count)?;
+        Ok(BitfieldRef { bytes, piece_count })
+    }
+
+    /// The number of pieces this bitfield covers.
+    pub fn piece_count(&self) -> u32 {
+        self.piece_count
+    }
+
+    /// The bitfield as it is sent on the wire.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Whether the piece is present. Pieces out of range are never present.
+    pub fn has_piece(&self, index: PieceIndex) -> bool {
+        index < self.piece_count && self.bytes[index as usize / 8] & mask(index) != 0
+    }
+
+    /// The number of pieces present.
+    ///
+    /// This is a `u64`, since the biggest bitfield a peer can send has 2^32 bits set.
+    pub fn count_set(&self) -> u64 {
+        self.bytes
+            .iter()
+            .map(|b| u64::from(b.count_ones()))
+            .sum::<u64>()
+            - u64::from(self.spare_bits().count_ones())
+    }
+
+    /// The indices of the pieces present, in ascending order.
+    pub fn iter_set_pieces(&self) -> impl Iterator<Item = PieceIndex> + 'a {
+        let piece_count = self.piece_count as u64;
+
+        self.bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte != 0)
+            .flat_map(|(i, byte)| {
+                (0..8u64)
+                    .filter(move |bit| byte & (0x80 >> bit) != 0)
+                    .map(move |bit| i as u64 * 8 + bit)
+            })
+            .take_while(move |index| *index < piece_count)
+            .map(|index| index as PieceIndex)
+    }
+
+    /// The bits of the last byte that fall after the last piece.
+    fn spare_bits(&self) -> u8 {
+        let used = self.piece_count % 8;
+
+        match self.bytes.last() {
+            Some(last) if used != 0 => last & (0xFF >> used),
+            _ => 0,
+        }
+    }
+}
+
+/// A deserialized bitfield, before it's been checked.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawBitfield {
+    bytes: Vec<u8>,
+    piece_count: u32,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawBitfield> for Bitfield {
+    type Error = BitfieldError;
+
+    fn try_from(raw: RawBitfield) -> Result<Bitfield, BitfieldError> {
+        Bitfield::from_bytes(&raw.bytes, raw.piece_count)
+    }
+}
+
+impl From<BitfieldRef<'_>> for Bitfield {
+    fn from(view: BitfieldRef<'_>) -> Bitfield {
+        Bitfield {
+            bytes: view.bytes.to_vec(),
+            piece_count: view.piece_count,
+        }
+    }
+}
+
+fn mask(index: PieceIndex) -> u8 {
+    0x80 >> (index % 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::Bitfield;
+    use crate::pwp::BitfieldError;
+    use crate::pwp::BitfieldRef;
+    use crate::pwp::Message;
+    use crate::pwp::ProtocolError;
+
+    #[test]
+    fn new_bitfield_is_empty() {
+        let bitfield = Bitfield::new(20);
+
+        assert_eq!(bitfield.as_bytes(), &[0, 0, 0]);
+        assert_eq!(bitfield.count_ones(), 0);
+        assert!(!bitfield.get(0));
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let mut bitfield = Bitfield::new(20);
+
+        bitfield.set(0).unwrap();
+        bitfield.set(9).unwrap();
+        bitfield.set(19).unwrap();
+
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40, 0x10]);
+        assert!(bitfield.get(9));
+        assert_eq!(bitfield.count_ones(), 3);
+
+        bitfield.clear(9).unwrap();
+
+        assert!(!bitfield.get(9));
+        assert_eq!(bitfield.count_ones(), 2);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut bitfield = Bitfield::new(20);
+
+        assert_eq!(
+            bitfield.set(20),
+            Err(BitfieldError::IndexOutOfRange {
+                index: 20,
+                piece_count: 20
+            })
+        );
+        assert!(bitfield.clear(20).is_err());
+        assert!(!bitfield.get(20));
+    }
+
+    #[test]
+    fn byte_len_at_max_piece_count() {
+        assert_eq!(Bitfield::byte_len(u32::MAX), 512 * 1024 * 1024);
+        assert_eq!(Bitfield::byte_len(u32::MAX - 7), 512 * 1024 * 1024 - 1);
+    }
+
+    #[test]
+    fn count_at_max_size() {
+        let payload = vec![0xFF; Bitfield::byte_len(u32::MAX)];
+        let view = BitfieldRef::from_payload(&payload);
+
+        assert_eq!(view.count_set(), u64::from(u32::MAX));
+        assert_eq!(
+            Message::Bitfield(Bitfield::from(view)).to_string(),
+            "Bitfield(4294967295 pieces, 4294967295 set)"
+        );
+    }
+
+    #[test]
+    fn max_piece_index() {
+        let mut bitfield = Bitfield::new(20);
+
+        assert!(bitfield.set(u32::MAX).is_err());
+        assert!(!bitfield.get(u32::MAX));
+        assert!(!bitfield.view().has_piece(u32::MAX));
+    }
+
+    #[test]
+    fn from_haves() {
+        let bitfield = Bitfield::from_haves([19, 0, 9, 0], 20).unwrap();
+
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40, 0x10]);
+        assert_eq!(
+            Bitfield::from_haves([3, 20], 20),
+            Err(BitfieldError::IndexOutOfRange {
+                index: 20,
+                piece_count: 20
+            })
+        );
+    }
+
+    #[test]
+    fn haves_round_trip() {
+        let bitfield = Bitfield::from_bytes(&[0x80, 0x40, 0x10], 20).unwrap();
+        let haves: Vec<Message> = bitfield.haves().collect();
+
+        assert_eq!(
+            haves,
+            vec![Message::Have(0), Message::Have(9), Message::Have(19)]
+        );
+
+        let indices = haves.into_iter().map(|msg| match msg {
+            Message::Have(index) => index,
+            other => panic!("expected a have, got {}", other),
+        });
+        assert_eq!(Bitfield::from_haves(indices, 20).unwrap(), bitfield);
+    }
+
+    #[test]
+    fn is_complete() {
+        let mut bitfield = Bitfield::new(10);
+        for index in 0..9 {
+            bitfield.set(index).unwrap();
+        }
+
+        assert!(!bitfield.is_complete());
+
+        bitfield.set(9).unwrap();
+
+        assert!(bitfield.is_complete());
+        assert!(Bitfield::new(0).is_complete());
+    }
+
+    #[test]
+    fn full_has_no_spare_bits() {
+        assert_eq!(Bitfield::full(12).as_bytes(), &[0xFF, 0xF0]);
+        assert_eq!(Bitfield::full(16).as_bytes(), &[0xFF, 0xFF]);
+        assert!(Bitfield::full(12).validate(12).is_ok());
+        assert!(Bitfield::full(12).is_complete());
+        assert!(Bitfield::full(0).as_bytes().is_empty());
+    }
+
+    #[test]
+    fn iterate_set_pieces() {
+        let bitfield = Bitfield::from_bytes(&[0x81, 0x00, 0x30], 20).unwrap();
+
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 7, 18, 19]);
+    }
+
+    #[test]
+    fn from_bytes_wrong_length() {
+        assert_eq!(
+            Bitfield::from_bytes(&[0xFF, 0xFF], 20),
+            Err(BitfieldError::TooShort {
+                expected: 3,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn validate_received_bitfield() {
+        let received = Bitfield::from(BitfieldRef::from_payload(&[0xFF, 0xC0]));
+
+        assert_eq!(received.validate(10), Ok(()));
+        assert_eq!(received.validate(16), Ok(()));
+        assert_eq!(
+            received.validate(9),
+            Err(ProtocolError::InvalidBitfield(BitfieldError::SpareBitsSet))
+        );
+        assert_eq!(
+            received.validate(17),
+            Err(ProtocolError::InvalidBitfield(BitfieldError::TooShort {
+                expected: 3,
+                actual: 2
+            }))
+        );
+        assert_eq!(
+            received.validate(8),
+            Err(ProtocolError::InvalidBitfield(BitfieldError::TooLong {
+                expected: 1,
+                actual: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn validate_len_errors() {
+        assert_eq!(Bitfield::validate_len(&[0xFF, 0xC0], 10), Ok(()));
+        assert_eq!(
+            Bitfield::validate_len(&[0xFF], 10),
+            Err(BitfieldError::TooShort {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            Bitfield::validate_len(&[0xFF, 0xC0, 0x00], 10),
+            Err(BitfieldError::TooLong {
+                expected: 2,
+                actual: 3
+            })
+        );
+        assert_eq!(
+            Bitfield::validate_len(&[0xFF, 0xE0], 10),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        assert_eq!(Bitfield::validate_len(&[], 0), Ok(()));
+        assert_eq!(Bitfield::validate_len(&[0xFF], 8), Ok(()));
+    }
+
+    #[test]
+    fn from_bytes_spare_bits_set() {
+        assert_eq!(
+            Bitfield::from_bytes(&[0xFF, 0xFF, 0xF8], 20),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        assert!(Bitfield::from_bytes(&[0xFF, 0xFF, 0xF0], 20).is_ok());
+    }
+
+    #[test]
+    fn from_payload_covers_every_bit() {
+        let bitfield = Bitfield::from(BitfieldRef::from_payload(&[0xFF, 0x01]));
+
+        assert_eq!(bitfield.piece_count(), 16);
+        assert_eq!(bitfield.count_ones(), 9);
+        assert!(bitfield.get(15));
+    }
+
+    #[test]
+    fn borrowed_matches_owned() {
+        let payload = [0b1010_0000, 0b0000_0001, 0b1100_0000];
+        let view = BitfieldRef::new(&payload, 18).unwrap();
+        let owned = Bitfield::from_bytes(&payload, 18).unwrap();
+
+        assert_eq!(view.count_set(), owned.count_ones());
+        assert_eq!(
+            view.iter_set_pieces().collect::<Vec<_>>(),
+            owned.iter().collect::<Vec<_>>()
+        );
+        for index in 0..20 {
+            assert_eq!(view.has_piece(index), owned.get(index));
+        }
+        assert_eq!(Bitfield::from(view), owned);
+        assert_eq!(owned.view(), view);
+    }
+
+    #[test]
+    fn borrowed_checks_like_owned() {
+        assert_eq!(
+            BitfieldRef::new(&[0x00, 0x20], 10),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        assert_eq!(
+            BitfieldRef::new(&[0x00], 10),
+            Err(BitfieldError::TooShort {
+                expected: 2,
+                actual: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Sending and receiving messages over a blocking `TcpStream`.
+
+use super::{default_max_frame_len, frame_len, HandshakeFlags, Message, ProtocolError};
+use bytes::BytesMut;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// A peer connection driven with blocking reads and writes.
+///
+/// Like `PwpCodec`, this only handles the messages that follow the handshake.
+#[derive(Debug)]
+pub struct BlockingPeer {
+    stream: TcpStream,
+    extensions: HandshakeFlags,
+    max_frame_len: u32,
+    write_buf: Vec<u8>,
+}
+
+impl BlockingPeer {
+    /// Wrap a stream whose connection negotiated no extensions.
+    pub fn new(stream: TcpStream) -> Self {
+        BlockingPeer::with_extensions(stream, HandshakeFlags::empty())
+    }
+
+    /// Wrap a stream, accepting messages from the negotiated `extensions`.
+    pub fn with_extensions(stream: TcpStream, extensions: HandshakeFlags) -> Self {
+        BlockingPeer {
+            stream,
+            extensions,
+            max_frame_len: default_max_frame_len(extensions),
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Refuse messages longer than `limit` bytes, not counting the length prefix.
+    ///
+    /// This starts at [`default_max_frame_len`](super::default_max_frame_len) for the negotiated
+    /// extensions. Raise it to accept bigger blocks,
+    /// or the bitfield of a torrent with more than 131072 pieces.
+    pub fn max_frame_len(mut self, limit: u32) -> Self {
+        self.max_frame_len = limit;
+        self
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    /// Write one length-prefixed message to the stream.
+    pub fn send(&mut self, msg: &Message) -> io::Result<()> {
+        self.write_buf.resize(msg.encoded_len(), 0);
+        let len = msg
+            .encode_to_slice(&mut self.write_buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.stream.write_all(&self.write_buf[..len])
+    }
+
+    /// Block until one whole message has been read from the stream.
+    ///
+    /// The header is checked before the body is read, and anything longer than
+    /// the [`max_frame_len`](BlockingPeer::max_frame_len) is refused,
+    /// so a bogus length can't make us wait on (or allocate for) a huge frame.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        let mut header = [0; 5];
+        self.stream.read_exact(&mut header[..4])?;
+        let header_len = if header[..4] == [0; 4] {
+            4
+        } else {
+            self.stream.read_exact(&mut header[4..])?;
+            5
+        };
+
+        let len = match frame_len(&header[..header_len], self.extensions) {
+            Ok(Some(len)) => len,
+            Ok(None) => {
+                u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize + 4
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        if len - 4 > self.max_frame_len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ProtocolError::PayloadTooLarge,
+            ));
+        }
+
+        let mut frame = BytesMut::zeroed(len);
+        frame[..header_len].copy_from_slice(&header[..header_len]);
+        self.stream.read_exact(&mut frame[header_len..])?;
+        if let Err(e) = frame_len(&frame, self.extensions) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        Ok(Message::from_frame(frame.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::BlockingPeer;
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::Message;
+    #[cfg(feature = "sha1")]
+    use crate::pwp::MetadataMessage;
+    use crate::pwp::DEFAULT_MAX_BLOCK_SIZE;
+    use crate::pwp::DEFAULT_MAX_FRAME_LEN;
+    #[cfg(feature = "sha1")]
+    use crate::pwp::METADATA_PIECE_LEN;
+    use bytes::Bytes;
+    use std::io::{ErrorKind, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn pair(extensions: HandshakeFlags) -> (BlockingPeer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (BlockingPeer::with_extensions(client, extensions), server)
+    }
+
+    #[test]
+    fn exchange_messages() {
+        let (mut a, server) = pair(HandshakeFlags::all());
+        let echo = thread::spawn(move || {
+            let mut b = BlockingPeer::with_extensions(server, HandshakeFlags::all());
+            for _ in 0..4 {
+                let msg = b.recv().unwrap();
+                b.send(&msg).unwrap();
+            }
+        });
+
+        let msgs = vec![
+            Message::KeepAlive,
+            Message::Interested,
+            Message::Block {
+                index: 1,
+                offset: 0x4000,
+                data: Bytes::from(vec![0xAB; 0x4000]),
+            },
+            Message::Port(6881),
+        ];
+        for msg in &msgs {
+            a.send(msg).unwrap();
+            assert_eq!(&a.recv().unwrap(), msg);
+        }
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn recv_rejects_bad_header() {
+        let (mut a, mut server) = pair(HandshakeFlags::empty());
+        server
+            .write_all(&[0x00, 0x00, 0x00, 0x03, 0x09, 0x1A, 0xE1])
+            .unwrap();
+
+        assert_eq!(a.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn recv_rejects_oversized_frame() {
+        let (mut a, mut server) = pair(HandshakeFlags::empty());
+        server.write_all(&[0xFF, 0xFF, 0xFF, 0xFF, 0x07]).unwrap();
+
+        assert_eq!(a.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn recv_allows_raised_frame_limit() {
+        let (a, server) = pair(HandshakeFlags::empty());
+        let mut a = a.max_frame_len(DEFAULT_MAX_FRAME_LEN + 1);
+        let mut b = BlockingPeer::new(server);
+        let msg = Message::Block {
+            index: 0,
+            offset: 0,
+            data: Bytes::from(vec![0; DEFAULT_MAX_BLOCK_SIZE as usize + 1]),
+        };
+
+        b.send(&msg).unwrap();
+
+        assert_eq!(a.recv().unwrap(), msg);
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn recv_full_size_metadata_piece() {
+        let (mut a, server) = pair(HandshakeFlags::EXTENDED);
+        let mut b = BlockingPeer::with_extensions(server, HandshakeFlags::EXTENDED);
+        let msg = MetadataMessage::Data {
+            piece: 0,
+            total_size: 10 * METADATA_PIECE_LEN,
+            data: Bytes::from(vec![0xAB; METADATA_PIECE_LEN as usize]),
+        }
+        .to_message(2);
+        assert!(msg.encoded_len() - 4 > DEFAULT_MAX_FRAME_LEN as usize);
+
+        b.send(&msg).unwrap();
+
+        assert_eq!(a.recv().unwrap(), msg);
+    }
+
+    #[test]
+    fn recv_rejects_bad_body() {
+        let (mut a, mut server) = pair(HandshakeFlags::DHT);
+        server
+            .write_all(&[0x00, 0x00, 0x00, 0x03, 0x09, 0x00, 0x00])
+            .unwrap();
+
+        assert_eq!(a.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn recv_reports_closed_stream() {
+        let (mut a, server) = pair(HandshakeFlags::empty());
+        drop(server);
+
+        assert_eq!(a.recv().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,243 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Deciding which peers to upload to: the choking algorithm, with an optimistic unchoke.
+
+use super::{ChokeFlag, Connection, InterestFlag, Message};
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long an optimistic unchoke lasts before it moves on to another peer.
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What the choking algorithm needs to know about a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// Bytes per second the peer has been sending us.
+    pub download_rate: f64,
+    pub interested: bool,
+    /// Whether we're currently letting the peer download from us.
+    pub unchoked: bool,
+}
+
+impl PeerStats {
+    /// The stats for the peer at `addr`, taken from our connection to them.
+    pub fn from_connection(addr: SocketAddr, conn: &Connection) -> Self {
+        PeerStats {
+            addr,
+            download_rate: conn.download_rate(),
+            interested: conn.them_interested() == InterestFlag::Interested,
+            unchoked: conn.us_choking() == ChokeFlag::Unchoked,
+        }
+    }
+}
+
+/// The peers whose choke state should change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChokeDecision {
+    /// Choked peers to unchoke.
+    pub unchoke: Vec<SocketAddr>,
+    /// Unchoked peers to choke.
+    pub choke: Vec<SocketAddr>,
+    /// The peer holding the optimistic unchoke, if any peer is eligible for it.
+    pub optimistic: Option<SocketAddr>,
+}
+
+impl ChokeDecision {
+    /// The `Choke` and `Unchoke` messages to send, and who to send them to.
+    pub fn messages(&self) -> impl Iterator<Item = (SocketAddr, Message)> + '_ {
+        let unchoke = self.unchoke.iter().map(|addr| (*addr, Message::Unchoke));
+        let choke = self.choke.iter().map(|addr| (*addr, Message::Choke));
+        unchoke.chain(choke)
+    }
+}
+
+/// Runs the choking algorithm, remembering the optimistic unchoke between rounds.
+///
+/// Call [`Choker::select_unchoked`] regularly, like every ten seconds.
+#[derive(Debug, Default)]
+pub struct Choker {
+    optimistic: Option<(SocketAddr, Instant)>,
+}
+
+impl Choker {
+    pub fn new() -> Self {
+        Choker::default()
+    }
+
+    /// Unchoke the `slots` interested peers sending to us fastest, plus one more interested peer
+    /// as an optimistic unchoke, so new peers get a chance to prove themselves.
+    ///
+    /// The optimistic unchoke stays with a peer for [`OPTIMISTIC_UNCHOKE_INTERVAL`],
+    /// then moves on to the next eligible peer in address order.
+    pub fn select_unchoked(
+        &mut self,
+        peers: &[PeerStats],
+        slots: usize,
+        now: Instant,
+    ) -> ChokeDecision {
+        let mut by_rate: Vec<&PeerStats> = peers.iter().filter(|p| p.interested).collect();
+        by_rate.sort_by(|a, b| {
+            b.download_rate
+                .partial_cmp(&a.download_rate)
+                .unwrap_or(Ordering::Equal)
+        });
+        let (fastest, rest) = by_rate.split_at(slots.min(by_rate.len()));
+
+        let mut candidates: Vec<SocketAddr> = rest.iter().map(|p| p.addr).collect();
+        candidates.sort_unstable();
+        let optimistic = self.next_optimistic(&candidates, now);
+
+        let unchoked = |addr: &SocketAddr| {
+            fastest.iter().any(|p| p.addr == *addr) || optimistic == Some(*addr)
+        };
+        ChokeDecision {
+            unchoke: peers
+                .iter()
+                .filter(|p| !p.unchoked && unchoked(&p.addr))
+                .map(|p| p.addr)
+                .collect(),
+            choke: peers
+                .iter()
+                .filter(|p| p.unchoked && !unchoked(&p.addr))
+                .map(|p| p.addr)
+                .collect(),
+            optimistic,
+        }
+    }
+
+    /// Keep the current optimistic unchoke if it's still eligible and fresh, otherwise rotate.
+    fn next_optimistic(&mut self, candidates: &[SocketAddr], now: Instant) -> Option<SocketAddr> {
+        if let Some((current, since)) = self.optimistic {
+            if candidates.contains(&current)
+                && now.saturating_duration_since(since) < OPTIMISTIC_UNCHOKE_INTERVAL
+            {
+                return Some(current);
+            }
+        }
+
+        let next = match self.optimistic {
+            Some((previous, _)) => candidates
+                .iter()
+                .find(|addr| **addr > previous)
+                .or_else(|| candidates.first()),
+            None => candidates.first(),
+        }
+        .copied();
+        self.optimistic = next.map(|addr| (addr, now));
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::ChokeDecision;
+    use crate::pwp::Choker;
+    use crate::pwp::Message;
+    use crate::pwp::PeerStats;
+    use crate::pwp::OPTIMISTIC_UNCHOKE_INTERVAL;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    fn peer(n: u8, download_rate: f64) -> PeerStats {
+        PeerStats {
+            addr: addr(n),
+            download_rate,
+            interested: true,
+            unchoked: false,
+        }
+    }
+
+    #[test]
+    fn fastest_peers_get_slots() {
+        let mut choker = Choker::new();
+        let peers = [
+            peer(1, 100.0),
+            peer(2, 500.0),
+            peer(3, 300.0),
+            peer(4, 200.0),
+        ];
+
+        let decision = choker.select_unchoked(&peers, 2, Instant::now());
+
+        // 2 and 3 are fastest; 1 comes first in address order for the optimistic slot.
+        assert_eq!(decision.unchoke, vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(decision.choke, vec![]);
+        assert_eq!(decision.optimistic, Some(addr(1)));
+    }
+
+    #[test]
+    fn uninterested_peers_are_choked() {
+        let mut choker = Choker::new();
+        let mut idle = peer(1, 1000.0);
+        idle.interested = false;
+        idle.unchoked = true;
+        let peers = [idle, peer(2, 10.0)];
+
+        let decision = choker.select_unchoked(&peers, 4, Instant::now());
+
+        assert_eq!(
+            decision,
+            ChokeDecision {
+                unchoke: vec![addr(2)],
+                choke: vec![addr(1)],
+                optimistic: None,
+            }
+        );
+        assert_eq!(
+            decision.messages().collect::<Vec<_>>(),
+            vec![(addr(2), Message::Unchoke), (addr(1), Message::Choke)]
+        );
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates() {
+        let mut choker = Choker::new();
+        let start = Instant::now();
+        let mut peers = [peer(1, 500.0), peer(2, 0.0), peer(3, 0.0), peer(4, 0.0)];
+
+        let first = choker.select_unchoked(&peers, 1, start);
+        assert_eq!(first.optimistic, Some(addr(2)));
+        for p in peers.iter_mut() {
+            p.unchoked = p.addr == addr(1) || p.addr == addr(2);
+        }
+
+        let held = choker.select_unchoked(&peers, 1, start + Duration::from_secs(10));
+        assert_eq!(held.optimistic, Some(addr(2)));
+        assert!(held.unchoke.is_empty() && held.choke.is_empty());
+
+        let rotated = choker.select_unchoked(&peers, 1, start + OPTIMISTIC_UNCHOKE_INTERVAL);
+        assert_eq!(rotated.optimistic, Some(addr(3)));
+        assert_eq!(rotated.unchoke, vec![addr(3)]);
+        assert_eq!(rotated.choke, vec![addr(2)]);
+    }
+
+    #[test]
+    fn optimistic_unchoke_wraps_around() {
+        let mut choker = Choker::new();
+        let start = Instant::now();
+        let peers = [peer(1, 0.0), peer(2, 0.0)];
+
+        assert_eq!(
+            choker.select_unchoked(&peers, 0, start).optimistic,
+            Some(addr(1))
+        );
+        let later = start + OPTIMISTIC_UNCHOKE_INTERVAL;
+        assert_eq!(
+            choker.select_unchoked(&peers, 0, later).optimistic,
+            Some(addr(2))
+        );
+        let later = later + OPTIMISTIC_UNCHOKE_INTERVAL;
+        assert_eq!(
+            choker.select_unchoked(&peers, 0, later).optimistic,
+            Some(addr(1))
+        );
+    }
+}
@@ -0,0 +1,582 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! A `tokio_util` codec for driving a connection with `Framed`,
+//! and the handshake exchange that comes before it.
+
+use super::{
+    default_max_frame_len, frame_len, try_encode_batch_into, Bitfield, EncodeError, Handshake,
+    HandshakeFlags, Message, ProtocolError, HANDSHAKE_LEN,
+};
+use bytes::BytesMut;
+use std::io;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+impl Handshake {
+    /// Send this handshake over `stream` and read the peer's in return.
+    ///
+    /// The peer's handshake is returned as-is; check it with [`Handshake::matches`].
+    pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        stream: &mut S,
+    ) -> Result<Handshake, CodecError> {
+        stream.write_all(&self.serialize()).await?;
+        stream.flush().await?;
+
+        let mut buf = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut buf).await?;
+
+        Ok(Handshake::deserialize(&buf)?)
+    }
+
+    /// Like [`exchange`](Handshake::exchange), but give up if it isn't finished within `timeout`.
+    ///
+    /// The deadline covers sending ours as well as receiving theirs,
+    /// so a peer that accepts the connection and then goes quiet can't hold us up.
+    pub async fn exchange_with_timeout<S: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        stream: &mut S,
+        timeout: Duration,
+    ) -> Result<Handshake, CodecError> {
+        tokio::time::timeout(timeout, self.exchange(stream))
+            .await
+            .map_err(|_| CodecError::TimedOut)?
+    }
+}
+
+/// Open a connection: send our handshake, followed by our bitfield if we have one.
+///
+/// Both go out in a single write, which is then flushed.
+pub async fn write_initiation<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    handshake: &Handshake,
+    bitfield: Option<&Bitfield>,
+) -> io::Result<()> {
+    let mut buf = BytesMut::with_capacity(HANDSHAKE_LEN);
+    handshake.serialize_into(&mut buf);
+    if let Some(bitfield) = bitfield {
+        Message::Bitfield(bitfield.clone()).serialize_into(&mut buf);
+    }
+
+    w.write_all(&buf).await?;
+    w.flush().await
+}
+
+/// Frames PWP messages on a byte stream.
+///
+/// This only handles the messages that follow the handshake,
+/// so install it once the handshake has been exchanged.
+#[derive(Debug)]
+pub struct PwpCodec {
+    extensions: HandshakeFlags,
+    max_frame_len: u32,
+    max_buffered: Option<usize>,
+    stall_timeout: Option<Duration>,
+    partial_since: Option<Instant>,
+}
+
+impl PwpCodec {
+    /// A codec for a connection that negotiated no extensions.
+    pub fn new() -> Self {
+        PwpCodec::with_extensions(HandshakeFlags::empty())
+    }
+
+    /// A codec that accepts messages from the negotiated `extensions`.
+    pub fn with_extensions(extensions: HandshakeFlags) -> Self {
+        PwpCodec {
+            extensions,
+            max_frame_len: default_max_frame_len(extensions),
+            max_buffered: None,
+            stall_timeout: None,
+            partial_since: None,
+        }
+    }
+
+    /// Refuse messages longer than `limit` bytes, not counting the length prefix.
+    ///
+    /// This starts at [`default_max_frame_len`](super::default_max_frame_len) for the negotiated
+    /// extensions, and is checked as soon as a message's header arrives.
+    pub fn max_frame_len(mut self, limit: u32) -> Self {
+        self.max_frame_len = limit;
+        self
+    }
+
+    /// Fail once more than `limit` bytes of an unfinished message are buffered.
+    ///
+    /// This must leave room for the largest message we accept, like a block and its header.
+    pub fn max_buffered(mut self, limit: usize) -> Self {
+        self.max_buffered = Some(limit);
+        self
+    }
+
+    /// Fail if a partly-received message isn't finished within `timeout`,
+    /// so a peer can't hold a frame open by dribbling bytes at us.
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    fn decode_at(
+        &mut self,
+        src: &mut BytesMut,
+        now: Instant,
+    ) -> Result<Option<Message>, CodecError> {
+        let len = frame_len(src, self.extensions)?;
+        if src.len() >= 4
+            && u32::from_be_bytes([src[0], src[1], src[2], src[3]]) > self.max_frame_len
+        {
+            return Err(ProtocolError::PayloadTooLarge.into());
+        }
+        if let Some(len) = len {
+            self.partial_since = None;
+            return Ok(Some(Message::from_frame(src.split_to(len).freeze())));
+        }
+
+        if let Some(limit) = self.max_buffered {
+            if src.len() > limit {
+                return Err(ProtocolError::BufferLimitExceeded {
+                    buffered: src.len(),
+                    limit,
+                }
+                .into());
+            }
+        }
+
+        if src.is_empty() {
+            self.partial_since = None;
+        } else {
+            let since = *self.partial_since.get_or_insert(now);
+            if let Some(timeout) = self.stall_timeout {
+                if now.duration_since(since) > timeout {
+                    return Err(ProtocolError::StalledFrame.into());
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Default for PwpCodec {
+    fn default() -> Self {
+        PwpCodec::new()
+    }
+}
+
+/// Errors from reading or writing framed messages.
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+    #[error("timed out waiting for the peer")]
+    TimedOut,
+}
+
+impl Decoder for PwpCodec {
+    type Item = Message;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        self.decode_at(src, Instant::now())
+    }
+}
+
+impl Encoder<Message> for PwpCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        msg.try_serialize_into(dst)?;
+        Ok(())
+    }
+}
+
+/// Encodes several messages back-to-back, so they go out in a single write,
+/// like a run of `Have`s from [`have_messages`](super::have_messages).
+///
+/// If any message can't be encoded, none of the batch is written.
+impl Encoder<Vec<Message>> for PwpCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, msgs: Vec<Message>, dst: &mut BytesMut) -> Result<(), CodecError> {
+        dst.reserve(msgs.iter().map(Message::encoded_len).sum());
+        try_encode_batch_into(msgs, dst)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::have_messages;
+    use crate::pwp::write_initiation;
+    use crate::pwp::Bitfield;
+    use crate::pwp::CodecError;
+    use crate::pwp::Handshake;
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::Message;
+    use crate::pwp::ProtocolError;
+    use crate::pwp::PwpCodec;
+    use crate::pwp::DEFAULT_MAX_FRAME_LEN;
+    use bytes::{Bytes, BytesMut};
+    use std::io;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn decode_waits_for_last_byte() {
+        let mut codec = PwpCodec::new();
+        let frame = Message::Request {
+            index: 1,
+            offset: 0,
+            length: 16384,
+        }
+        .serialize();
+
+        let mut buf = BytesMut::from(&frame[..frame.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], &frame[..frame.len() - 1]);
+
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Message::Request {
+                index: 1,
+                offset: 0,
+                length: 16384,
+            })
+        );
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_stalled_frame() {
+        let mut codec = PwpCodec::new().stall_timeout(Duration::from_secs(30));
+        let frame = Message::Have(23).serialize();
+        let start = Instant::now();
+
+        let mut buf = BytesMut::from(&frame[..2]);
+        assert!(codec.decode_at(&mut buf, start).unwrap().is_none());
+        buf.extend_from_slice(&frame[2..4]);
+        assert!(codec
+            .decode_at(&mut buf, start + Duration::from_secs(20))
+            .unwrap()
+            .is_none());
+
+        match codec.decode_at(&mut buf, start + Duration::from_secs(31)) {
+            Err(CodecError::Protocol(ProtocolError::StalledFrame)) => {}
+            other => panic!("expected a stalled frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_resets_stall_timer() {
+        let mut codec = PwpCodec::new().stall_timeout(Duration::from_secs(30));
+        let mut frames = BytesMut::new();
+        frames.extend_from_slice(&Message::Have(1).serialize());
+        frames.extend_from_slice(&Message::Have(2).serialize());
+        let start = Instant::now();
+
+        let mut buf = BytesMut::from(&frames[..5]);
+        assert!(codec.decode_at(&mut buf, start).unwrap().is_none());
+        buf.extend_from_slice(&frames[5..11]);
+        let later = start + Duration::from_secs(29);
+        assert_eq!(
+            codec.decode_at(&mut buf, later).unwrap(),
+            Some(Message::Have(1))
+        );
+        assert!(codec.decode_at(&mut buf, later).unwrap().is_none());
+
+        buf.extend_from_slice(&frames[11..]);
+        let much_later = later + Duration::from_secs(29);
+        assert_eq!(
+            codec.decode_at(&mut buf, much_later).unwrap(),
+            Some(Message::Have(2))
+        );
+    }
+
+    #[test]
+    fn decode_buffer_limit_ignores_complete_frames() {
+        let mut codec = PwpCodec::new().max_buffered(8);
+        let mut buf = BytesMut::new();
+        for index in 0..3 {
+            buf.extend_from_slice(&Message::Have(index).serialize());
+        }
+
+        for index in 0..3 {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(index)));
+        }
+    }
+
+    #[test]
+    fn decode_buffer_limit() {
+        let mut codec = PwpCodec::new().max_buffered(16);
+        let mut buf = BytesMut::from(&[0x00, 0x00, 0x40, 0x09, 0x07][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[0; 12]);
+
+        match codec.decode(&mut buf) {
+            Err(CodecError::Protocol(ProtocolError::BufferLimitExceeded {
+                buffered: 17,
+                limit: 16,
+            })) => {}
+            other => panic!("expected the buffer limit to be hit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_split_frame() {
+        let mut codec = PwpCodec::new();
+        let frame = Message::Block {
+            index: 666,
+            offset: 420,
+            data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+        }
+        .serialize();
+
+        let mut buf = BytesMut::from(&frame[..7]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&frame[7..]);
+        let msg = codec.decode(&mut buf).unwrap();
+
+        assert_eq!(
+            msg,
+            Some(Message::Block {
+                index: 666,
+                offset: 420,
+                data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+            })
+        );
+        assert!(buf.is_empty());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn encode_then_decode() {
+        let mut codec = PwpCodec::new();
+        let mut buf = BytesMut::new();
+
+        codec.encode(Message::Interested, &mut buf).unwrap();
+        codec.encode(Message::Have(23), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Interested));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(23)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn encode_batch_of_haves() {
+        let mut codec = PwpCodec::new();
+        let mut buf = BytesMut::new();
+
+        codec
+            .encode(have_messages(0..5).collect::<Vec<_>>(), &mut buf)
+            .unwrap();
+
+        assert_eq!(buf.len(), 5 * 9);
+        for index in 0..5 {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(index)));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn encode_batch_with_unencodable_message_writes_nothing() {
+        let mut codec = PwpCodec::new();
+        let mut buf = BytesMut::new();
+        let msgs = vec![
+            Message::Have(0),
+            Message::Unknown {
+                id: 0x04,
+                payload: Bytes::from_static(&[0, 0, 0, 1]),
+            },
+        ];
+
+        assert!(matches!(
+            codec.encode(msgs, &mut buf),
+            Err(CodecError::Encode(_))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_oversized_block_header() {
+        let mut codec = PwpCodec::new();
+        let mut buf = BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF, 0x07][..]);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CodecError::Protocol(ProtocolError::PayloadTooLarge))
+        ));
+    }
+
+    #[test]
+    fn decode_with_raised_frame_limit() {
+        let msg = Message::Block {
+            index: 0,
+            offset: 0,
+            data: Bytes::from(vec![0; DEFAULT_MAX_FRAME_LEN as usize]),
+        };
+        let mut buf = BytesMut::new();
+        PwpCodec::new().encode(msg.clone(), &mut buf).unwrap();
+
+        assert!(PwpCodec::new().decode(&mut buf.clone()).is_err());
+        let mut codec = PwpCodec::new().max_frame_len(DEFAULT_MAX_FRAME_LEN + 9);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+    }
+
+    #[test]
+    fn decode_invalid_frame() {
+        let mut codec = PwpCodec::new();
+        let mut buf = BytesMut::from(&[0x00, 0x00, 0x00, 0x02, 0x04, 0x00][..]);
+
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn decode_negotiated_extension() {
+        let mut codec = PwpCodec::with_extensions(HandshakeFlags::FAST);
+        let mut buf = BytesMut::new();
+
+        codec.encode(Message::HaveAll, &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::HaveAll));
+    }
+
+    #[tokio::test]
+    async fn exchange_handshakes() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let info_hash = *b"12345678901234567890";
+        let us = Handshake::strict(info_hash, *b"Landslide Experiment");
+        let them = Handshake::new(info_hash, *b"-XX0001-123456789012", HandshakeFlags::FAST);
+
+        let (ours, theirs) = tokio::join!(
+            us.clone().exchange(&mut ours),
+            them.clone().exchange(&mut theirs)
+        );
+
+        assert_eq!(ours.unwrap(), them);
+        assert_eq!(theirs.unwrap(), us);
+    }
+
+    #[tokio::test]
+    async fn exchange_short_read() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let us = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+
+        let peer = async move {
+            let mut buf = [0; 68];
+            theirs.read_exact(&mut buf).await.unwrap();
+            theirs.write_all(&buf[..40]).await.unwrap();
+        };
+        let (result, ()) = tokio::join!(us.exchange(&mut ours), peer);
+
+        match result {
+            Err(CodecError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("expected an unexpected EOF, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn exchange_with_timeout_in_time() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let info_hash = *b"12345678901234567890";
+        let us = Handshake::strict(info_hash, *b"Landslide Experiment");
+        let them = Handshake::new(info_hash, *b"-XX0001-123456789012", HandshakeFlags::FAST);
+
+        let (ours, theirs) = tokio::join!(
+            us.clone()
+                .exchange_with_timeout(&mut ours, Duration::from_secs(5)),
+            them.clone().exchange(&mut theirs)
+        );
+
+        assert_eq!(ours.unwrap(), them);
+        assert_eq!(theirs.unwrap(), us);
+    }
+
+    #[tokio::test]
+    async fn exchange_with_silent_peer_times_out() {
+        let (mut ours, _theirs) = tokio::io::duplex(256);
+        let us = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+
+        let result = us
+            .exchange_with_timeout(&mut ours, Duration::from_millis(10))
+            .await;
+
+        assert!(matches!(result, Err(CodecError::TimedOut)));
+    }
+
+    #[tokio::test]
+    async fn exchange_with_full_pipe_times_out() {
+        // The peer never reads, so our handshake can't even be written.
+        let (mut ours, _theirs) = tokio::io::duplex(16);
+        let us = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+
+        let result = us
+            .exchange_with_timeout(&mut ours, Duration::from_millis(10))
+            .await;
+
+        assert!(matches!(result, Err(CodecError::TimedOut)));
+    }
+
+    #[tokio::test]
+    async fn exchange_wrong_protocol() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let us = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+
+        let peer = async move {
+            let mut buf = [0; 68];
+            theirs.read_exact(&mut buf).await.unwrap();
+            buf[1..20].copy_from_slice(b"BitTorrent Protocol");
+            theirs.write_all(&buf).await.unwrap();
+        };
+        let (result, ()) = tokio::join!(us.exchange(&mut ours), peer);
+
+        match result {
+            Err(CodecError::Protocol(e)) => assert_eq!(e, ProtocolError::InvalidProtocolString),
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn write_initiation_with_bitfield() {
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(3).unwrap();
+        let mut written = Vec::new();
+
+        write_initiation(&mut written, &handshake, Some(&bitfield))
+            .await
+            .unwrap();
+
+        assert_eq!(Handshake::deserialize(&written[..68]), Ok(handshake));
+        let mut rest = BytesMut::from(&written[68..]);
+        assert_eq!(
+            PwpCodec::new().decode(&mut rest).unwrap(),
+            Some(Message::Bitfield(
+                Bitfield::from_bytes(bitfield.as_bytes(), 16).unwrap()
+            ))
+        );
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_initiation_without_bitfield() {
+        let handshake = Handshake::strict(*b"12345678901234567890", *b"Landslide Experiment");
+        let mut written = Vec::new();
+
+        write_initiation(&mut written, &handshake, None)
+            .await
+            .unwrap();
+
+        assert_eq!(&written[..], &handshake.serialize()[..]);
+    }
+}
@@ -0,0 +1,976 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Tracking the state of our connections to peers.
+
+use super::{Bitfield, ChokeFlag, DownloadStatus, InterestFlag, Message, ProtocolError};
+use crate::{BlockCoord, PieceIndex};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Connection {
+    us_choking: ChokeFlag,
+    us_interested: InterestFlag,
+    them_choking: ChokeFlag,
+    them_interested: InterestFlag,
+    requests_sent_at: HashMap<BlockCoord, Instant>,
+    rtt: Option<Duration>,
+    last_sent_at: Option<Instant>,
+    bytes_uploaded: u64,
+    bytes_downloaded: u64,
+    rate_window: Option<Duration>,
+    /// Snapshots of the byte counters, as (when, uploaded, downloaded), oldest first.
+    transfer_samples: VecDeque<(Instant, u64, u64)>,
+    /// The pieces the peer has told us about, once we've asked to track them.
+    peer_pieces: Option<Bitfield>,
+}
+
+/// How far back transfer rates look unless set otherwise.
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(20);
+
+impl Connection {
+    /// Start over for a peer that has reconnected.
+    ///
+    /// Choke and interest go back to their initial states, and outstanding request timings,
+    /// keep-alive timing and rate samples are forgotten. The round-trip time estimate,
+    /// the byte counters and the rate window persist, since they describe the peer
+    /// rather than the connection. If we were tracking the peer's pieces we still are,
+    /// but starting from none, since the peer will announce them again.
+    pub fn reconnect(&mut self) {
+        *self = Connection {
+            rtt: self.rtt,
+            bytes_uploaded: self.bytes_uploaded,
+            bytes_downloaded: self.bytes_downloaded,
+            rate_window: self.rate_window,
+            peer_pieces: self
+                .peer_pieces
+                .as_ref()
+                .map(|pieces| Bitfield::new(pieces.piece_count())),
+            ..Connection::default()
+        };
+    }
+
+    /// Keep track of which of the torrent's `piece_count` pieces the peer has,
+    /// starting from none.
+    ///
+    /// From then on [`apply_incoming`](Connection::apply_incoming) keeps it up to date
+    /// from the peer's `Bitfield`, `Have`, `HaveAll` and `HaveNone` messages.
+    pub fn track_peer_pieces(&mut self, piece_count: u32) {
+        self.peer_pieces = Some(Bitfield::new(piece_count));
+    }
+
+    /// The pieces the peer has, if we're tracking them.
+    pub fn peer_pieces(&self) -> Option<&Bitfield> {
+        self.peer_pieces.as_ref()
+    }
+
+    /// Whether the peer has told us it has a piece.
+    ///
+    /// Always false if we aren't tracking the peer's pieces.
+    pub fn peer_has(&self, index: PieceIndex) -> bool {
+        self.peer_pieces
+            .as_ref()
+            .is_some_and(|pieces| pieces.get(index))
+    }
+
+    /// Whether we're choking the peer.
+    pub fn us_choking(&self) -> ChokeFlag {
+        self.us_choking
+    }
+
+    /// Whether we're interested in the peer.
+    pub fn us_interested(&self) -> InterestFlag {
+        self.us_interested
+    }
+
+    /// Whether the peer is choking us.
+    pub fn them_choking(&self) -> ChokeFlag {
+        self.them_choking
+    }
+
+    /// Whether the peer is interested in us.
+    pub fn them_interested(&self) -> InterestFlag {
+        self.them_interested
+    }
+
+    /// Start choking the peer, returning the message to tell them.
+    pub fn choke_peer(&mut self) -> Message {
+        self.us_choking = ChokeFlag::Choked;
+        Message::Choke
+    }
+
+    /// Stop choking the peer, returning the message to tell them.
+    pub fn unchoke_peer(&mut self) -> Message {
+        self.us_choking = ChokeFlag::Unchoked;
+        Message::Unchoke
+    }
+
+    /// Become interested in the peer, returning the message to tell them.
+    pub fn set_interested(&mut self) -> Message {
+        self.us_interested = InterestFlag::Interested;
+        Message::Interested
+    }
+
+    /// Lose interest in the peer, returning the message to tell them.
+    pub fn set_not_interested(&mut self) -> Message {
+        self.us_interested = InterestFlag::NotInterested;
+        Message::Uninterested
+    }
+
+    /// Update the peer's side of the connection for a message they sent us.
+    ///
+    /// If we're tracking the peer's pieces, a `Bitfield` of the wrong size or a `Have`
+    /// for a piece out of range is an error, and the peer should be dropped.
+    /// The peer's pieces are left as they were.
+    pub fn apply_incoming(&mut self, msg: &Message) -> Result<(), ProtocolError> {
+        self.bytes_downloaded += msg.encoded_len() as u64;
+        match msg {
+            Message::Choke => self.them_choking = ChokeFlag::Choked,
+            Message::Unchoke => self.them_choking = ChokeFlag::Unchoked,
+            Message::Interested => self.them_interested = InterestFlag::Interested,
+            Message::Uninterested => self.them_interested = InterestFlag::NotInterested,
+            Message::Have(index) => {
+                if let Some(pieces) = &mut self.peer_pieces {
+                    pieces.set(*index)?;
+                }
+            }
+            Message::Bitfield(bitfield) => {
+                if let Some(pieces) = &mut self.peer_pieces {
+                    *pieces = Bitfield::from_bytes(bitfield.as_bytes(), pieces.piece_count())?;
+                }
+            }
+            Message::HaveAll => {
+                if let Some(pieces) = &mut self.peer_pieces {
+                    *pieces = Bitfield::full(pieces.piece_count());
+                }
+            }
+            Message::HaveNone => {
+                if let Some(pieces) = &mut self.peer_pieces {
+                    *pieces = Bitfield::new(pieces.piece_count());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether we may send the peer requests, i.e. we're interested and they aren't choking us.
+    pub fn can_request(&self) -> bool {
+        matches!(self.us_interested, InterestFlag::Interested)
+            && matches!(self.them_choking, ChokeFlag::Unchoked)
+    }
+
+    /// How our download from this peer is going, given how many of our requests are outstanding.
+    pub fn download_status(&self, outstanding_requests: usize) -> DownloadStatus {
+        match self.them_choking {
+            ChokeFlag::Choked => DownloadStatus::Choked,
+            ChokeFlag::Unchoked if outstanding_requests == 0 => DownloadStatus::Idle,
+            ChokeFlag::Unchoked => DownloadStatus::Active,
+        }
+    }
+
+    /// Whether we want data from this peer but aren't getting any.
+    pub fn is_download_stalled(&self, outstanding_requests: usize) -> bool {
+        matches!(self.us_interested, InterestFlag::Interested)
+            && self.download_status(outstanding_requests) != DownloadStatus::Active
+    }
+
+    /// Remember when we requested a block, so we can time how long the peer takes to send it.
+    pub fn note_request_sent(
+        &mut self,
+        index: crate::PieceIndex,
+        offset: crate::BlockOffset,
+        now: Instant,
+    ) {
+        self.requests_sent_at
+            .insert(BlockCoord::new(index, offset), now);
+    }
+
+    /// Record the arrival of a block, returning how long it took if we requested it.
+    pub fn note_block_received(
+        &mut self,
+        index: crate::PieceIndex,
+        offset: crate::BlockOffset,
+        now: Instant,
+    ) -> Option<Duration> {
+        let sent_at = self
+            .requests_sent_at
+            .remove(&BlockCoord::new(index, offset))?;
+        let sample = now.saturating_duration_since(sent_at);
+
+        // Smooth the estimate the same way TCP does, weighting the new sample by 1/8.
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+
+        Some(sample)
+    }
+
+    /// The smoothed round-trip time of our block requests, if any have completed.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Account for a message we just sent the peer.
+    ///
+    /// A choke or interest message updates our side of the connection, like
+    /// [`apply_incoming`](Connection::apply_incoming) does for the peer's.
+    pub fn record_sent(&mut self, msg: &Message, now: Instant) {
+        self.bytes_uploaded += msg.encoded_len() as u64;
+        self.last_sent_at = Some(now);
+        match msg {
+            Message::Choke => self.us_choking = ChokeFlag::Choked,
+            Message::Unchoke => self.us_choking = ChokeFlag::Unchoked,
+            Message::Interested => self.us_interested = InterestFlag::Interested,
+            Message::Uninterested => self.us_interested = InterestFlag::NotInterested,
+            _ => {}
+        }
+    }
+
+    /// Every byte we've sent the peer, counting whole messages.
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.bytes_uploaded
+    }
+
+    /// Every byte the peer has sent us, counting whole messages.
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.bytes_downloaded
+    }
+
+    /// Zero the byte counters and forget the rate samples.
+    pub fn reset_stats(&mut self) {
+        self.bytes_uploaded = 0;
+        self.bytes_downloaded = 0;
+        self.transfer_samples.clear();
+    }
+
+    /// Set how far back `upload_rate` and `download_rate` look.
+    pub fn set_rate_window(&mut self, window: Duration) {
+        self.rate_window = Some(window);
+    }
+
+    /// Snapshot the byte counters, for the rate estimates.
+    ///
+    /// Call this regularly, like on every rechoke; rates are measured between snapshots.
+    pub fn sample_transfer(&mut self, now: Instant) {
+        let window = self.rate_window.unwrap_or(DEFAULT_RATE_WINDOW);
+        self.transfer_samples
+            .push_back((now, self.bytes_uploaded, self.bytes_downloaded));
+        while let Some((oldest, _, _)) = self.transfer_samples.front() {
+            if now.saturating_duration_since(*oldest) <= window {
+                break;
+            }
+            self.transfer_samples.pop_front();
+        }
+    }
+
+    /// Bytes per second sent to the peer across the samples in the rate window.
+    pub fn upload_rate(&self) -> f64 {
+        self.rate(|(_, uploaded, _)| *uploaded)
+    }
+
+    /// Bytes per second received from the peer across the samples in the rate window.
+    pub fn download_rate(&self) -> f64 {
+        self.rate(|(_, _, downloaded)| *downloaded)
+    }
+
+    fn rate(&self, bytes: impl Fn(&(Instant, u64, u64)) -> u64) -> f64 {
+        match (self.transfer_samples.front(), self.transfer_samples.back()) {
+            (Some(first), Some(last)) => {
+                let elapsed = last.0.saturating_duration_since(first.0).as_secs_f64();
+                if elapsed == 0.0 {
+                    0.0
+                } else {
+                    (bytes(last) - bytes(first)) as f64 / elapsed
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Whether we've been quiet for `interval` and should send a keep-alive.
+    ///
+    /// A connection we've never recorded sending anything on always needs one.
+    pub fn needs_keepalive(&self, now: Instant, interval: Duration) -> bool {
+        match self.last_sent_at {
+            Some(sent_at) => now.saturating_duration_since(sent_at) >= interval,
+            None => true,
+        }
+    }
+
+    /// The messages to send so that our choke and interest state matches the targets.
+    ///
+    /// Nothing is sent for a flag that is already in the target state.
+    pub fn transition_to(
+        &self,
+        target_us_choking: ChokeFlag,
+        target_us_interested: InterestFlag,
+    ) -> Vec<Message> {
+        let mut msgs = Vec::new();
+
+        match (&self.us_choking, target_us_choking) {
+            (ChokeFlag::Unchoked, ChokeFlag::Choked) => msgs.push(Message::Choke),
+            (ChokeFlag::Choked, ChokeFlag::Unchoked) => msgs.push(Message::Unchoke),
+            _ => {}
+        }
+
+        match (&self.us_interested, target_us_interested) {
+            (InterestFlag::NotInterested, InterestFlag::Interested) => {
+                msgs.push(Message::Interested)
+            }
+            (InterestFlag::Interested, InterestFlag::NotInterested) => {
+                msgs.push(Message::Uninterested)
+            }
+            _ => {}
+        }
+
+        msgs
+    }
+
+    /// Whether a `Request` from this peer should be ignored because we are choking them.
+    pub fn should_ignore_request(&self) -> bool {
+        matches!(self.us_choking, ChokeFlag::Choked)
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Connection[us: {}/{}, them: {}/{}, up={} down={}]",
+            self.us_choking,
+            self.us_interested,
+            self.them_choking,
+            self.them_interested,
+            ByteCount(self.bytes_uploaded),
+            ByteCount(self.bytes_downloaded)
+        )
+    }
+}
+
+/// A byte count abbreviated for logs, like `1.2MB`.
+struct ByteCount(u64);
+
+impl fmt::Display for ByteCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["kB", "MB", "GB", "TB"];
+
+        if self.0 < 1000 {
+            return write!(f, "{}B", self.0);
+        }
+        let mut value = self.0 as f64 / 1000.0;
+        let mut unit = 0;
+        while value >= 1000.0 && unit < UNITS.len() - 1 {
+            value /= 1000.0;
+            unit += 1;
+        }
+        write!(f, "{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Connections to many peers, keyed by the peer's address.
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    connections: HashMap<SocketAddr, Connection>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the connection to a peer, starting a fresh one if we don't have one yet.
+    pub fn get_or_insert(&mut self, addr: SocketAddr) -> &mut Connection {
+        self.connections.entry(addr).or_default()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&Connection> {
+        self.connections.get(addr)
+    }
+
+    pub fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut Connection> {
+        self.connections.get_mut(addr)
+    }
+
+    /// Forget about a peer, returning its connection if there was one.
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<Connection> {
+        self.connections.remove(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &Connection)> {
+        self.connections.iter()
+    }
+
+    /// The number of peers we are not choking, i.e. the upload slots in use.
+    pub fn unchoked_count(&self) -> usize {
+        self.count(|c| matches!(c.us_choking, ChokeFlag::Unchoked))
+    }
+
+    /// The number of peers we are interested in.
+    pub fn interesting_count(&self) -> usize {
+        self.count(|c| matches!(c.us_interested, InterestFlag::Interested))
+    }
+
+    /// The number of peers that are not choking us.
+    pub fn unchoking_us_count(&self) -> usize {
+        self.count(|c| matches!(c.them_choking, ChokeFlag::Unchoked))
+    }
+
+    /// The number of peers that are interested in us.
+    pub fn interested_count(&self) -> usize {
+        self.count(|c| matches!(c.them_interested, InterestFlag::Interested))
+    }
+
+    /// Bytes per second we're receiving from all peers together.
+    ///
+    /// Each connection's rate comes from its own samples, so take them with
+    /// [`Connection::sample_transfer`] first.
+    pub fn total_download_rate(&self) -> f64 {
+        self.connections
+            .values()
+            .map(Connection::download_rate)
+            .sum()
+    }
+
+    fn count(&self, predicate: impl Fn(&Connection) -> bool) -> usize {
+        self.connections.values().filter(|c| predicate(c)).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::Bitfield;
+    use crate::pwp::BitfieldError;
+    use crate::pwp::ChokeFlag;
+    use crate::pwp::Connection;
+    use crate::pwp::ConnectionRegistry;
+    use crate::pwp::DownloadStatus;
+    use crate::pwp::InterestFlag;
+    use crate::pwp::Message;
+    use crate::pwp::ProtocolError;
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn registry_get_or_insert() {
+        let mut registry = ConnectionRegistry::new();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        registry.get_or_insert(addr).us_choking = ChokeFlag::Unchoked;
+        registry.get_or_insert(addr);
+
+        assert_eq!(registry.len(), 1);
+        assert!(matches!(
+            registry.get(&addr).unwrap().us_choking,
+            ChokeFlag::Unchoked
+        ));
+    }
+
+    #[test]
+    fn registry_remove() {
+        let mut registry = ConnectionRegistry::new();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        registry.get_or_insert(addr);
+
+        assert!(registry.remove(&addr).is_some());
+        assert!(registry.remove(&addr).is_none());
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn registry_aggregates() {
+        let mut registry = ConnectionRegistry::new();
+        let a: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6882".parse().unwrap();
+        let c: SocketAddr = "[::1]:6881".parse().unwrap();
+
+        let conn = registry.get_or_insert(a);
+        conn.us_choking = ChokeFlag::Unchoked;
+        conn.them_interested = InterestFlag::Interested;
+
+        let conn = registry.get_or_insert(b);
+        conn.us_interested = InterestFlag::Interested;
+        conn.them_choking = ChokeFlag::Unchoked;
+        conn.them_interested = InterestFlag::Interested;
+
+        registry.get_or_insert(c);
+
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.unchoked_count(), 1);
+        assert_eq!(registry.interesting_count(), 1);
+        assert_eq!(registry.unchoking_us_count(), 1);
+        assert_eq!(registry.interested_count(), 2);
+    }
+
+    #[test]
+    fn registry_total_download_rate() {
+        let mut registry = ConnectionRegistry::new();
+        assert_eq!(registry.total_download_rate(), 0.0);
+
+        let start = Instant::now();
+        let later = start + Duration::from_secs(2);
+        for (port, bytes) in [(6881, 1000), (6882, 3000)] {
+            let conn = registry.get_or_insert(SocketAddr::from(([127, 0, 0, 1], port)));
+            conn.sample_transfer(start);
+            conn.bytes_downloaded += bytes;
+            conn.sample_transfer(later);
+        }
+        registry.get_or_insert(SocketAddr::from(([127, 0, 0, 1], 6883)));
+
+        assert_eq!(registry.total_download_rate(), 2000.0);
+    }
+
+    #[test]
+    fn connection_flag_getters() {
+        let mut conn = Connection::default();
+        conn.unchoke_peer();
+        conn.apply_incoming(&Message::Interested).unwrap();
+
+        assert_eq!(conn.us_choking(), ChokeFlag::Unchoked);
+        assert_eq!(conn.us_interested(), InterestFlag::NotInterested);
+        assert_eq!(conn.them_choking(), ChokeFlag::Choked);
+        assert_eq!(conn.them_interested(), InterestFlag::Interested);
+    }
+
+    #[test]
+    fn keepalive_after_quiet_interval() {
+        let mut conn = Connection::default();
+        let start = Instant::now();
+        let interval = Duration::from_secs(90);
+
+        assert!(conn.needs_keepalive(start, interval));
+
+        conn.record_sent(&Message::KeepAlive, start);
+        assert!(!conn.needs_keepalive(start + Duration::from_secs(89), interval));
+        assert!(conn.needs_keepalive(start + interval, interval));
+
+        conn.record_sent(&Message::KeepAlive, start + Duration::from_secs(60));
+        assert!(!conn.needs_keepalive(start + interval, interval));
+    }
+
+    #[test]
+    fn reconnect_resets_flags() {
+        let mut conn = Connection::default();
+        let start = Instant::now();
+        conn.unchoke_peer();
+        conn.set_interested();
+        conn.apply_incoming(&Message::Unchoke).unwrap();
+        conn.note_request_sent(0, 0, start);
+        conn.note_block_received(0, 0, start + Duration::from_millis(100));
+        conn.note_request_sent(0, 16384, start);
+        conn.record_sent(&Message::KeepAlive, start);
+
+        conn.reconnect();
+
+        assert_eq!(conn.us_choking(), ChokeFlag::Choked);
+        assert_eq!(conn.us_interested(), InterestFlag::NotInterested);
+        assert_eq!(conn.them_choking(), ChokeFlag::Choked);
+        assert!(conn.needs_keepalive(start, Duration::from_secs(90)));
+        assert_eq!(conn.note_block_received(0, 16384, start), None);
+        assert_eq!(conn.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(conn.bytes_uploaded(), 4);
+        assert_eq!(conn.bytes_downloaded(), 5);
+    }
+
+    #[test]
+    fn record_sent_updates_our_flags() {
+        let mut conn = Connection::default();
+        let now = Instant::now();
+
+        conn.record_sent(&Message::Unchoke, now);
+        conn.record_sent(&Message::Interested, now);
+        assert_eq!(conn.us_choking(), ChokeFlag::Unchoked);
+        assert_eq!(conn.us_interested(), InterestFlag::Interested);
+        assert_eq!(conn.them_choking(), ChokeFlag::Choked);
+
+        conn.record_sent(&Message::Choke, now);
+        conn.record_sent(&Message::Uninterested, now);
+        assert_eq!(conn.us_choking(), ChokeFlag::Choked);
+        assert_eq!(conn.us_interested(), InterestFlag::NotInterested);
+    }
+
+    #[test]
+    fn count_transferred_bytes() {
+        let mut conn = Connection::default();
+        let block = Message::Block {
+            index: 0,
+            offset: 0,
+            data: Bytes::from(vec![0; 100]),
+        };
+
+        conn.record_sent(&block, Instant::now());
+        conn.apply_incoming(&Message::Have(1)).unwrap();
+        conn.apply_incoming(&Message::KeepAlive).unwrap();
+
+        assert_eq!(conn.bytes_uploaded(), 113);
+        assert_eq!(conn.bytes_downloaded(), 13);
+
+        conn.reset_stats();
+        assert_eq!(conn.bytes_uploaded(), 0);
+        assert_eq!(conn.bytes_downloaded(), 0);
+    }
+
+    #[test]
+    fn rolling_transfer_rate() {
+        let mut conn = Connection::default();
+        conn.set_rate_window(Duration::from_secs(20));
+        let start = Instant::now();
+        let block = |len| Message::Block {
+            index: 0,
+            offset: 0,
+            data: Bytes::from(vec![0; len]),
+        };
+
+        conn.sample_transfer(start);
+        assert_eq!(conn.download_rate(), 0.0);
+
+        conn.apply_incoming(&block(9987)).unwrap();
+        conn.sample_transfer(start + Duration::from_secs(10));
+        assert_eq!(conn.download_rate(), 1000.0);
+        assert_eq!(conn.upload_rate(), 0.0);
+
+        conn.sample_transfer(start + Duration::from_secs(20));
+        assert_eq!(conn.download_rate(), 500.0);
+
+        // The first sample has now fallen out of the window.
+        conn.sample_transfer(start + Duration::from_secs(30));
+        assert_eq!(conn.download_rate(), 0.0);
+    }
+
+    #[test]
+    fn download_status_choked() {
+        let conn = Connection {
+            us_interested: InterestFlag::Interested,
+            ..Connection::default()
+        };
+
+        assert_eq!(conn.download_status(0), DownloadStatus::Choked);
+        assert_eq!(conn.download_status(3), DownloadStatus::Choked);
+        assert!(conn.is_download_stalled(3));
+    }
+
+    #[test]
+    fn download_status_idle() {
+        let conn = Connection {
+            us_interested: InterestFlag::Interested,
+            them_choking: ChokeFlag::Unchoked,
+            ..Connection::default()
+        };
+
+        assert_eq!(conn.download_status(0), DownloadStatus::Idle);
+        assert!(conn.is_download_stalled(0));
+    }
+
+    #[test]
+    fn download_status_active() {
+        let conn = Connection {
+            us_interested: InterestFlag::Interested,
+            them_choking: ChokeFlag::Unchoked,
+            ..Connection::default()
+        };
+
+        assert_eq!(conn.download_status(3), DownloadStatus::Active);
+        assert!(!conn.is_download_stalled(3));
+    }
+
+    #[test]
+    fn download_not_stalled_when_uninterested() {
+        let conn = Connection::default();
+
+        assert_eq!(conn.download_status(0), DownloadStatus::Choked);
+        assert!(!conn.is_download_stalled(0));
+    }
+
+    #[test]
+    fn ignore_requests_while_choking() {
+        let conn = Connection::default();
+
+        assert!(conn.should_ignore_request());
+    }
+
+    #[test]
+    fn serve_requests_while_unchoked() {
+        let conn = Connection {
+            us_choking: ChokeFlag::Unchoked,
+            ..Connection::default()
+        };
+
+        assert!(!conn.should_ignore_request());
+    }
+
+    #[test]
+    fn measure_request_rtt() {
+        let mut conn = Connection::default();
+        let start = Instant::now();
+
+        conn.note_request_sent(666, 420, start);
+        let rtt = conn.note_block_received(666, 420, start + Duration::from_millis(150));
+
+        assert_eq!(rtt, Some(Duration::from_millis(150)));
+        assert_eq!(conn.rtt(), Some(Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn smooth_request_rtt() {
+        let mut conn = Connection::default();
+        let start = Instant::now();
+
+        conn.note_request_sent(1, 0, start);
+        conn.note_block_received(1, 0, start + Duration::from_millis(100));
+        conn.note_request_sent(2, 0, start);
+        conn.note_block_received(2, 0, start + Duration::from_millis(900));
+
+        assert_eq!(conn.rtt(), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn unrequested_block_has_no_rtt() {
+        let mut conn = Connection::default();
+
+        assert_eq!(conn.note_block_received(666, 420, Instant::now()), None);
+        assert_eq!(conn.rtt(), None);
+    }
+
+    #[test]
+    fn display_connection() {
+        let conn = Connection {
+            us_choking: ChokeFlag::Unchoked,
+            us_interested: InterestFlag::Interested,
+            ..Connection::default()
+        };
+
+        assert_eq!(
+            conn.to_string(),
+            "Connection[us: unchoked/interested, them: choked/not-interested, up=0B down=0B]"
+        );
+    }
+
+    #[test]
+    fn display_connection_byte_counters() {
+        let conn = Connection {
+            them_choking: ChokeFlag::Unchoked,
+            bytes_uploaded: 1_200_000,
+            bytes_downloaded: 3_400_000,
+            ..Connection::default()
+        };
+
+        assert_eq!(
+            conn.to_string(),
+            "Connection[us: choked/not-interested, them: unchoked/not-interested, up=1.2MB down=3.4MB]"
+        );
+
+        let conn = Connection {
+            bytes_uploaded: 999,
+            bytes_downloaded: 5_600_000_000_000_000,
+            ..Connection::default()
+        };
+        assert!(conn.to_string().ends_with("up=999B down=5600.0TB]"));
+    }
+
+    #[test]
+    fn transition_from_default() {
+        let conn = Connection::default();
+
+        let msgs = conn.transition_to(ChokeFlag::Unchoked, InterestFlag::Interested);
+
+        assert!(matches!(
+            msgs.as_slice(),
+            [Message::Unchoke, Message::Interested]
+        ));
+    }
+
+    #[test]
+    fn transition_to_current_state() {
+        let conn = Connection::default();
+
+        let msgs = conn.transition_to(ChokeFlag::Choked, InterestFlag::NotInterested);
+
+        assert!(msgs.is_empty());
+    }
+
+    #[test]
+    fn transition_skips_flags_already_set() {
+        let conn = Connection {
+            us_choking: ChokeFlag::Unchoked,
+            us_interested: InterestFlag::Interested,
+            ..Connection::default()
+        };
+
+        let msgs = conn.transition_to(ChokeFlag::Unchoked, InterestFlag::NotInterested);
+        assert!(matches!(msgs.as_slice(), [Message::Uninterested]));
+
+        let msgs = conn.transition_to(ChokeFlag::Choked, InterestFlag::Interested);
+        assert!(matches!(msgs.as_slice(), [Message::Choke]));
+    }
+
+    #[test]
+    fn local_state_changes() {
+        let mut conn = Connection::default();
+
+        assert_eq!(conn.unchoke_peer(), Message::Unchoke);
+        assert_eq!(conn.us_choking, ChokeFlag::Unchoked);
+        assert_eq!(conn.choke_peer(), Message::Choke);
+        assert_eq!(conn.us_choking, ChokeFlag::Choked);
+
+        assert_eq!(conn.set_interested(), Message::Interested);
+        assert_eq!(conn.us_interested, InterestFlag::Interested);
+        assert_eq!(conn.set_not_interested(), Message::Uninterested);
+        assert_eq!(conn.us_interested, InterestFlag::NotInterested);
+    }
+
+    #[test]
+    fn apply_incoming_state_messages() {
+        let mut conn = Connection::default();
+
+        conn.apply_incoming(&Message::Unchoke).unwrap();
+        conn.apply_incoming(&Message::Interested).unwrap();
+
+        assert_eq!(conn.them_choking, ChokeFlag::Unchoked);
+        assert_eq!(conn.them_interested, InterestFlag::Interested);
+
+        conn.apply_incoming(&Message::Choke).unwrap();
+        conn.apply_incoming(&Message::Uninterested).unwrap();
+
+        assert_eq!(conn.them_choking, ChokeFlag::Choked);
+        assert_eq!(conn.them_interested, InterestFlag::NotInterested);
+    }
+
+    #[test]
+    fn untracked_peer_has_nothing() {
+        let mut conn = Connection::default();
+
+        conn.apply_incoming(&Message::HaveAll).unwrap();
+        conn.apply_incoming(&Message::Have(3)).unwrap();
+
+        assert!(conn.peer_pieces().is_none());
+        assert!(!conn.peer_has(3));
+    }
+
+    #[test]
+    fn track_peer_bitfield_and_haves() {
+        let mut conn = Connection::default();
+        conn.track_peer_pieces(12);
+        assert!(!conn.peer_has(3));
+
+        let mut bitfield = Bitfield::new(12);
+        bitfield.set(3).unwrap();
+        conn.apply_incoming(&Message::Bitfield(bitfield)).unwrap();
+        assert!(conn.peer_has(3));
+        assert!(!conn.peer_has(11));
+
+        conn.apply_incoming(&Message::Have(11)).unwrap();
+        assert!(conn.peer_has(11));
+        assert_eq!(conn.peer_pieces().unwrap().count_ones(), 2);
+    }
+
+    #[test]
+    fn track_peer_have_all_and_none() {
+        let mut conn = Connection::default();
+        conn.track_peer_pieces(12);
+
+        conn.apply_incoming(&Message::HaveAll).unwrap();
+        assert!(conn.peer_pieces().unwrap().is_complete());
+        assert!(conn.peer_has(11));
+        assert!(!conn.peer_has(12));
+
+        conn.apply_incoming(&Message::HaveNone).unwrap();
+        assert!(!conn.peer_has(11));
+        assert_eq!(conn.peer_pieces().unwrap().count_ones(), 0);
+    }
+
+    #[test]
+    fn track_peer_rejects_have_out_of_range() {
+        let mut conn = Connection::default();
+        conn.track_peer_pieces(12);
+        conn.apply_incoming(&Message::Have(0)).unwrap();
+
+        assert_eq!(
+            conn.apply_incoming(&Message::Have(12)),
+            Err(ProtocolError::InvalidBitfield(
+                BitfieldError::IndexOutOfRange {
+                    index: 12,
+                    piece_count: 12
+                }
+            ))
+        );
+        assert_eq!(
+            conn.peer_pieces().unwrap().iter().collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn track_peer_rejects_mis_sized_bitfield() {
+        let mut conn = Connection::default();
+        conn.track_peer_pieces(12);
+        conn.apply_incoming(&Message::Have(0)).unwrap();
+
+        assert_eq!(
+            conn.apply_incoming(&Message::Bitfield(Bitfield::full(20))),
+            Err(ProtocolError::InvalidBitfield(BitfieldError::TooLong {
+                expected: 2,
+                actual: 3
+            }))
+        );
+        assert_eq!(
+            conn.apply_incoming(&Message::Bitfield(Bitfield::full(16))),
+            Err(ProtocolError::InvalidBitfield(BitfieldError::SpareBitsSet))
+        );
+        assert_eq!(
+            conn.peer_pieces().unwrap().iter().collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn untracked_peer_accepts_any_availability() {
+        let mut conn = Connection::default();
+
+        assert_eq!(conn.apply_incoming(&Message::Have(12)), Ok(()));
+        assert_eq!(
+            conn.apply_incoming(&Message::Bitfield(Bitfield::full(20))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reconnect_forgets_peer_pieces() {
+        let mut conn = Connection::default();
+        conn.track_peer_pieces(12);
+        conn.apply_incoming(&Message::HaveAll).unwrap();
+
+        conn.reconnect();
+
+        assert_eq!(conn.peer_pieces(), Some(&Bitfield::new(12)));
+    }
+
+    #[test]
+    fn can_request_when_interested_and_unchoked() {
+        let mut conn = Connection::default();
+        assert!(!conn.can_request());
+
+        conn.set_interested();
+        assert!(!conn.can_request());
+
+        conn.apply_incoming(&Message::Unchoke).unwrap();
+        assert!(conn.can_request());
+
+        conn.set_not_interested();
+        assert!(!conn.can_request());
+    }
+}
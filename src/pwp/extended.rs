@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! The Extension Protocol (BEP 10).
+
+use super::{Message, ProtocolError};
+use crate::bencode::{self, BencodeValue as Value};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use bytes::Bytes;
+use core::convert::TryFrom;
+
+/// The extended message ID reserved for the extended handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The handshake sent as extended message 0, announcing which extensions we support.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// The `m` dictionary: the extended message ID the sender wants each extension sent on.
+    pub extensions: BTreeMap<String, u8>,
+    /// The `v` string: the sender's client name and version.
+    pub client: Option<String>,
+    /// The `p` integer: the port the sender accepts connections on.
+    pub listen_port: Option<u16>,
+    /// The `metadata_size` integer: how long the info dictionary is, for `ut_metadata`.
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap this handshake in the message that carries it.
+    pub fn to_message(&self) -> Message {
+        Message::Extended {
+            ext_id: EXTENDED_HANDSHAKE_ID,
+            payload: self.to_payload(),
+        }
+    }
+
+    /// Bencode this handshake.
+    pub fn to_payload(&self) -> Bytes {
+        let mut dict = BTreeMap::new();
+
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Integer(*id as i64)))
+            .collect();
+        dict.insert(b"m".to_vec(), Value::Dict(m));
+
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), Value::Integer(size as i64));
+        }
+        if let Some(port) = self.listen_port {
+            dict.insert(b"p".to_vec(), Value::Integer(port as i64));
+        }
+        if let Some(client) = &self.client {
+            dict.insert(b"v".to_vec(), Value::Bytes(client.as_bytes().to_vec()));
+        }
+
+        bencode::encode(&Value::Dict(dict))
+    }
+
+    /// Parse the bencoded payload of an extended handshake.
+    ///
+    /// Keys we don't know about, and values of the wrong type, are ignored.
+    /// So are extensions given ID 0, which BEP 10 uses to mean the peer has disabled them.
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let dict = match bencode::decode(payload) {
+            Ok(Value::Dict(dict)) => dict,
+            _ => return Err(ProtocolError::InvalidExtendedHandshake),
+        };
+
+        let mut handshake = ExtendedHandshake::new();
+
+        if let Some(Value::Dict(m)) = dict.get(&b"m"[..]) {
+            for (name, id) in m {
+                if let (Ok(name), Value::Integer(id)) = (core::str::from_utf8(name), id) {
+                    if let Some(id) = u8::try_from(*id).ok().filter(|id| *id != 0) {
+                        handshake.extensions.insert(name.to_string(), id);
+                    }
+                }
+            }
+        }
+
+        if let Some(Value::Bytes(v)) = dict.get(&b"v"[..]) {
+            handshake.client = Some(String::from_utf8_lossy(v).into_owned());
+        }
+
+        if let Some(Value::Integer(p)) = dict.get(&b"p"[..]) {
+            handshake.listen_port = u16::try_from(*p).ok().filter(|p| *p != 0);
+        }
+
+        if let Some(Value::Integer(size)) = dict.get(&b"metadata_size"[..]) {
+            handshake.metadata_size = u32::try_from(*size).ok();
+        }
+
+        Ok(handshake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::ExtendedHandshake;
+    use crate::pwp::Message;
+    use crate::pwp::ProtocolError;
+
+    #[test]
+    fn round_trip_extended_handshake() {
+        let mut handshake = ExtendedHandshake::new();
+        handshake.extensions.insert("ut_metadata".to_string(), 3);
+        handshake.client = Some("Landslide 0.1.0".to_string());
+        handshake.listen_port = Some(6881);
+        handshake.metadata_size = Some(31235);
+
+        let payload = handshake.to_payload();
+
+        assert_eq!(ExtendedHandshake::from_payload(&payload), Ok(handshake));
+    }
+
+    #[test]
+    fn encode_extended_handshake() {
+        let mut handshake = ExtendedHandshake::new();
+        handshake.extensions.insert("ut_metadata".to_string(), 3);
+        handshake.listen_port = Some(6881);
+
+        assert_eq!(
+            &handshake.to_payload()[..],
+            b"d1:md11:ut_metadatai3ee1:pi6881ee"
+        );
+    }
+
+    #[test]
+    fn parse_extended_handshake() {
+        let payload = b"d1:md11:ut_metadatai2e6:ut_pexi1ee1:pi51413e1:v12:uTorrent 1.2e";
+
+        let handshake = ExtendedHandshake::from_payload(payload).unwrap();
+
+        assert_eq!(handshake.extensions.get("ut_pex"), Some(&1));
+        assert_eq!(handshake.extensions.get("ut_metadata"), Some(&2));
+        assert_eq!(handshake.listen_port, Some(51413));
+        assert_eq!(handshake.client.as_deref(), Some("uTorrent 1.2"));
+    }
+
+    #[test]
+    fn parse_extended_handshake_ignores_unknown_keys() {
+        let payload = b"d1:md6:ut_pexi1ee4:reqqi250e6:yourip4:\x7f\x00\x00\x01e";
+
+        let handshake = ExtendedHandshake::from_payload(payload).unwrap();
+
+        assert_eq!(handshake.extensions.get("ut_pex"), Some(&1));
+        assert_eq!(handshake.listen_port, None);
+    }
+
+    #[test]
+    fn parse_extended_handshake_skips_disabled_extensions() {
+        let payload = b"d1:md11:ut_metadatai0e6:ut_pexi1eee";
+
+        let handshake = ExtendedHandshake::from_payload(payload).unwrap();
+
+        assert_eq!(handshake.extensions.get("ut_metadata"), None);
+        assert_eq!(handshake.extensions.get("ut_pex"), Some(&1));
+    }
+
+    #[test]
+    fn parse_invalid_extended_handshake() {
+        assert_eq!(
+            ExtendedHandshake::from_payload(b"li1ee"),
+            Err(ProtocolError::InvalidExtendedHandshake)
+        );
+        assert_eq!(
+            ExtendedHandshake::from_payload(b"d1:md"),
+            Err(ProtocolError::InvalidExtendedHandshake)
+        );
+    }
+
+    #[test]
+    fn extended_handshake_message() {
+        let msg = ExtendedHandshake::new().to_message();
+
+        assert!(matches!(msg, Message::Extended { ext_id: 0, .. }));
+    }
+}
@@ -0,0 +1,118 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! `Arbitrary` implementations for fuzzing and property tests.
+
+use super::{BitfieldRef, Handshake, Message, MessageId};
+use arbitrary::{Arbitrary, Result, Unstructured};
+use bytes::Bytes;
+
+/// The largest bitfield or block payload generated, so fuzz inputs stay small.
+const MAX_PAYLOAD_LEN: usize = 16 * 1024;
+
+impl<'a> Arbitrary<'a> for Handshake {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Handshake {
+            reserved: u.arbitrary()?,
+            info_hash: u.arbitrary()?,
+            peer_id: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Message {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let msg = match u.int_in_range(0..=17)? {
+            0 => Message::KeepAlive,
+            1 => Message::Choke,
+            2 => Message::Unchoke,
+            3 => Message::Interested,
+            4 => Message::Uninterested,
+            5 => Message::Have(u.arbitrary()?),
+            6 => Message::Bitfield(BitfieldRef::from_payload(&payload(u)?).into()),
+            7 => Message::Request {
+                index: u.arbitrary()?,
+                offset: u.arbitrary()?,
+                length: u.arbitrary()?,
+            },
+            8 => Message::Cancel {
+                index: u.arbitrary()?,
+                offset: u.arbitrary()?,
+                length: u.arbitrary()?,
+            },
+            9 => Message::Block {
+                index: u.arbitrary()?,
+                offset: u.arbitrary()?,
+                data: payload(u)?,
+            },
+            10 => Message::SuggestPiece {
+                index: u.arbitrary()?,
+            },
+            11 => Message::HaveAll,
+            12 => Message::HaveNone,
+            13 => Message::RejectRequest {
+                index: u.arbitrary()?,
+                offset: u.arbitrary()?,
+                length: u.arbitrary()?,
+            },
+            14 => Message::AllowedFast {
+                index: u.arbitrary()?,
+            },
+            15 => Message::Port(u.int_in_range(1..=u16::MAX)?),
+            16 => Message::Extended {
+                ext_id: u.arbitrary()?,
+                payload: payload(u)?,
+            },
+            _ => Message::Unknown {
+                id: unmodelled_id(u)?,
+                payload: payload(u)?,
+            },
+        };
+        Ok(msg)
+    }
+}
+
+/// An ID no other variant models, so the message decodes back as `Unknown`.
+fn unmodelled_id(u: &mut Unstructured) -> Result<u8> {
+    let ids: Vec<u8> = (0..=u8::MAX)
+        .filter(|id| MessageId::from_u8(*id).is_none())
+        .collect();
+    u.choose(&ids).copied()
+}
+
+fn payload(u: &mut Unstructured) -> Result<Bytes> {
+    let len = u.int_in_range(0..=MAX_PAYLOAD_LEN)?.min(u.len());
+    Ok(Bytes::copy_from_slice(u.bytes(len)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::Handshake;
+    use crate::pwp::Message;
+    use arbitrary::{Arbitrary, Unstructured};
+
+    #[test]
+    fn arbitrary_handshake_serializes() {
+        let raw: Vec<u8> = (0..=255).collect();
+        let mut u = Unstructured::new(&raw);
+
+        let handshake = Handshake::arbitrary(&mut u).unwrap();
+
+        assert_eq!(handshake.serialize().len(), 68);
+    }
+
+    #[test]
+    fn arbitrary_messages_serialize() {
+        let raw: Vec<u8> = (0..4096).map(|i| (i * 7 % 251) as u8).collect();
+        let mut u = Unstructured::new(&raw);
+
+        for _ in 0..16 {
+            let msg = Message::arbitrary(&mut u).unwrap();
+            let buf = msg.serialize();
+
+            assert!(buf.len() >= 4);
+            assert!(buf.len() <= 13 + super::MAX_PAYLOAD_LEN);
+        }
+    }
+}
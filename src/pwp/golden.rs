@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Golden wire-format vectors for every message and the handshake.
+//!
+//! If one of these fails, the bytes we put on the wire have changed.
+
+use crate::pwp::{Bitfield, Handshake, HandshakeFlags, Message};
+use bytes::Bytes;
+
+fn hex(s: &str) -> Vec<u8> {
+    let s: String = s.split_whitespace().collect();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn assert_golden(msg: Message, expected: &str) {
+    assert_eq!(msg.serialize().to_vec(), hex(expected));
+}
+
+#[test]
+fn golden_handshake() {
+    let handshake = Handshake::new(
+        *b"12345678901234567890",
+        *b"Landslide Experiment",
+        HandshakeFlags::FAST | HandshakeFlags::DHT,
+    );
+
+    assert_eq!(
+        handshake.serialize().to_vec(),
+        hex("13 426974546f7272656e742070726f746f636f6c
+             0000000000000401
+             3132333435363738393031323334353637383930
+             4c616e64736c696465204578706572696d656e74")
+    );
+}
+
+#[test]
+fn golden_keepalive() {
+    assert_golden(Message::KeepAlive, "00000000");
+}
+
+#[test]
+fn golden_choke() {
+    assert_golden(Message::Choke, "00000001 00");
+}
+
+#[test]
+fn golden_unchoke() {
+    assert_golden(Message::Unchoke, "00000001 01");
+}
+
+#[test]
+fn golden_interested() {
+    assert_golden(Message::Interested, "00000001 02");
+}
+
+#[test]
+fn golden_uninterested() {
+    assert_golden(Message::Uninterested, "00000001 03");
+}
+
+#[test]
+fn golden_have() {
+    assert_golden(Message::Have(23), "00000005 04 00000017");
+}
+
+#[test]
+fn golden_bitfield() {
+    assert_golden(
+        Message::Bitfield(Bitfield::from_bytes(&[0xFF, 0xFF, 0xFF], 24).unwrap()),
+        "00000004 05 ffffff",
+    );
+}
+
+#[test]
+fn golden_request() {
+    assert_golden(
+        Message::Request {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        },
+        "0000000d 06 0000029a 000001a4 00004000",
+    );
+}
+
+#[test]
+fn golden_block() {
+    assert_golden(
+        Message::Block {
+            index: 666,
+            offset: 420,
+            data: Bytes::from(vec![4, 8, 15, 16, 23, 42]),
+        },
+        "0000000f 07 0000029a 000001a4 04080f10172a",
+    );
+}
+
+#[test]
+fn golden_cancel() {
+    assert_golden(
+        Message::Cancel {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        },
+        "0000000d 08 0000029a 000001a4 00004000",
+    );
+}
+
+#[test]
+fn golden_port() {
+    assert_golden(Message::Port(6881), "00000003 09 1ae1");
+}
+
+#[test]
+fn golden_suggest_piece() {
+    assert_golden(Message::SuggestPiece { index: 23 }, "00000005 0d 00000017");
+}
+
+#[test]
+fn golden_have_all() {
+    assert_golden(Message::HaveAll, "00000001 0e");
+}
+
+#[test]
+fn golden_have_none() {
+    assert_golden(Message::HaveNone, "00000001 0f");
+}
+
+#[test]
+fn golden_reject_request() {
+    assert_golden(
+        Message::RejectRequest {
+            index: 666,
+            offset: 420,
+            length: 16384,
+        },
+        "0000000d 10 0000029a 000001a4 00004000",
+    );
+}
+
+#[test]
+fn golden_allowed_fast() {
+    assert_golden(Message::AllowedFast { index: 23 }, "00000005 11 00000017");
+}
+
+#[test]
+fn golden_extended() {
+    assert_golden(
+        Message::Extended {
+            ext_id: 3,
+            payload: Bytes::from_static(b"de"),
+        },
+        "00000004 14 03 6465",
+    );
+}
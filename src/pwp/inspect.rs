@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! A machine-readable view of messages, for inspection tools and snapshots.
+
+use super::Message;
+use serde_json::{json, Value};
+
+impl Message {
+    /// Describe this message as JSON.
+    ///
+    /// The object has the message's `kind`, its `fields`, and its `encoded_len` on the wire.
+    /// Payloads are summarized by their length rather than included.
+    pub fn to_json(&self) -> String {
+        let (kind, fields) = match self {
+            Message::KeepAlive => ("keep_alive", json!({})),
+            Message::Choke => ("choke", json!({})),
+            Message::Unchoke => ("unchoke", json!({})),
+            Message::Interested => ("interested", json!({})),
+            Message::Uninterested => ("not_interested", json!({})),
+            Message::Have(index) => ("have", json!({ "index": index })),
+            Message::Bitfield(b) => (
+                "bitfield",
+                json!({ "piece_count": b.piece_count(), "have_count": b.count_ones() }),
+            ),
+            Message::Request {
+                index,
+                offset,
+                length,
+            } => (
+                "request",
+                json!({ "index": index, "offset": offset, "length": length }),
+            ),
+            Message::Cancel {
+                index,
+                offset,
+                length,
+            } => (
+                "cancel",
+                json!({ "index": index, "offset": offset, "length": length }),
+            ),
+            Message::Block {
+                index,
+                offset,
+                data,
+            } => (
+                "block",
+                json!({ "index": index, "offset": offset, "data_len": data.len() }),
+            ),
+            Message::Port(port) => ("port", json!({ "port": port })),
+            Message::SuggestPiece { index } => ("suggest_piece", json!({ "index": index })),
+            Message::HaveAll => ("have_all", json!({})),
+            Message::HaveNone => ("have_none", json!({})),
+            Message::RejectRequest {
+                index,
+                offset,
+                length,
+            } => (
+                "reject_request",
+                json!({ "index": index, "offset": offset, "length": length }),
+            ),
+            Message::AllowedFast { index } => ("allowed_fast", json!({ "index": index })),
+            Message::Extended { ext_id, payload } => (
+                "extended",
+                json!({ "ext_id": ext_id, "payload_len": payload.len() }),
+            ),
+            Message::Unknown { id, payload } => {
+                ("unknown", json!({ "id": id, "payload_len": payload.len() }))
+            }
+        };
+
+        let value: Value = json!({
+            "kind": kind,
+            "fields": fields,
+            "encoded_len": self.encoded_len(),
+        });
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::Bitfield;
+    use crate::pwp::ChokeFlag;
+    use crate::pwp::Handshake;
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::InterestFlag;
+    use crate::pwp::Message;
+    use bytes::Bytes;
+
+    #[test]
+    fn request_to_json() {
+        let msg = Message::Request {
+            index: 1,
+            offset: 16384,
+            length: 16384,
+        };
+
+        assert_eq!(
+            msg.to_json(),
+            r#"{"encoded_len":17,"fields":{"index":1,"length":16384,"offset":16384},"kind":"request"}"#
+        );
+    }
+
+    #[test]
+    fn block_to_json_omits_data() {
+        let msg = Message::Block {
+            index: 1,
+            offset: 0,
+            data: Bytes::from_static(b"abc"),
+        };
+
+        assert_eq!(
+            msg.to_json(),
+            r#"{"encoded_len":16,"fields":{"data_len":3,"index":1,"offset":0},"kind":"block"}"#
+        );
+    }
+
+    #[test]
+    fn keep_alive_to_json() {
+        assert_eq!(
+            Message::KeepAlive.to_json(),
+            r#"{"encoded_len":4,"fields":{},"kind":"keep_alive"}"#
+        );
+    }
+
+    #[test]
+    fn serde_round_trip_messages() {
+        for msg in crate::pwp::tests::every_variant() {
+            let json = serde_json::to_string(&msg).unwrap();
+            assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn serde_round_trip_handshake() {
+        let handshake = Handshake::new(
+            *b"12345678901234567890",
+            *b"Landslide Experiment",
+            HandshakeFlags::FAST | HandshakeFlags::EXTENDED,
+        );
+
+        let json = serde_json::to_string(&handshake).unwrap();
+        assert_eq!(serde_json::from_str::<Handshake>(&json).unwrap(), handshake);
+    }
+
+    #[test]
+    fn serde_flags() {
+        let flags = HandshakeFlags::FAST | HandshakeFlags::DHT;
+
+        assert_eq!(serde_json::to_string(&flags).unwrap(), "1025");
+        assert_eq!(
+            serde_json::from_str::<HandshakeFlags>("1025").unwrap(),
+            flags
+        );
+        assert_eq!(
+            serde_json::from_str::<ChokeFlag>(
+                &serde_json::to_string(&ChokeFlag::Unchoked).unwrap()
+            )
+            .unwrap(),
+            ChokeFlag::Unchoked
+        );
+        assert_eq!(
+            serde_json::from_str::<InterestFlag>(
+                &serde_json::to_string(&InterestFlag::Interested).unwrap()
+            )
+            .unwrap(),
+            InterestFlag::Interested
+        );
+    }
+
+    #[test]
+    fn serde_checks_bitfield() {
+        let bitfield = Bitfield::from_bytes(&[0xFF, 0xC0], 10).unwrap();
+        let json = serde_json::to_string(&bitfield).unwrap();
+
+        assert_eq!(json, r#"{"bytes":[255,192],"piece_count":10}"#);
+        assert_eq!(serde_json::from_str::<Bitfield>(&json).unwrap(), bitfield);
+        assert!(
+            serde_json::from_str::<Bitfield>(r#"{"bytes":[255,224],"piece_count":10}"#).is_err()
+        );
+    }
+}
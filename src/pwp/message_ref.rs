@@ -0,0 +1,326 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Messages parsed in place, borrowing their payloads from the receive buffer.
+
+use super::{frame_len, BitfieldRef, HandshakeFlags, Message, MessageId, ProtocolError};
+use crate::{BlockOffset, BlockSize, PieceIndex};
+use bytes::{Buf, Bytes};
+
+/// A [`Message`] whose `Block`, `Bitfield` and `Extended` payloads borrow from the buffer it was parsed from.
+///
+/// Parsing one never copies a payload, so blocks can be written straight to disk from the receive buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageRef<'a> {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    Uninterested,
+    Have(PieceIndex),
+    Bitfield(BitfieldRef<'a>),
+    Request {
+        index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockSize,
+    },
+    Cancel {
+        index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockSize,
+    },
+    Block {
+        index: PieceIndex,
+        offset: BlockOffset,
+        data: &'a [u8],
+    },
+    Port(u16),
+    SuggestPiece {
+        index: PieceIndex,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockSize,
+    },
+    AllowedFast {
+        index: PieceIndex,
+    },
+    Extended {
+        ext_id: u8,
+        payload: &'a [u8],
+    },
+    Unknown {
+        id: u8,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parse the message at the front of `buf`, assuming no extensions were negotiated.
+    ///
+    /// Returns `Ok(None)` if `buf` doesn't hold a complete message yet.
+    /// Otherwise the message took up its [`encoded_len`](MessageRef::encoded_len) bytes of `buf`.
+    pub fn parse(buf: &'a [u8]) -> Result<Option<MessageRef<'a>>, ProtocolError> {
+        MessageRef::parse_with(buf, HandshakeFlags::empty())
+    }
+
+    /// Parse the message at the front of `buf`, accepting messages from the negotiated `extensions`.
+    ///
+    /// Returns `Ok(None)` if `buf` doesn't hold a complete message yet.
+    pub fn parse_with(
+        buf: &'a [u8],
+        extensions: HandshakeFlags,
+    ) -> Result<Option<MessageRef<'a>>, ProtocolError> {
+        match frame_len(buf, extensions)? {
+            Some(len) => Ok(Some(MessageRef::from_frame(&buf[..len]))),
+            None => Ok(None),
+        }
+    }
+
+    /// Parse a whole frame, length prefix included, that has already been checked by `frame_len`.
+    pub(crate) fn from_frame(mut frame: &'a [u8]) -> MessageRef<'a> {
+        frame.advance(4);
+
+        if frame.is_empty() {
+            return MessageRef::KeepAlive;
+        }
+
+        let id = frame.get_u8();
+        let id = match MessageId::from_u8(id) {
+            Some(id) => id,
+            None => return MessageRef::Unknown { id, payload: frame },
+        };
+        match id {
+            MessageId::Choke => MessageRef::Choke,
+            MessageId::Unchoke => MessageRef::Unchoke,
+            MessageId::Interested => MessageRef::Interested,
+            MessageId::Uninterested => MessageRef::Uninterested,
+            MessageId::Have => MessageRef::Have(frame.get_u32()),
+            MessageId::Bitfield => MessageRef::Bitfield(BitfieldRef::from_payload(frame)),
+            MessageId::Request => MessageRef::Request {
+                index: frame.get_u32(),
+                offset: frame.get_u32(),
+                length: frame.get_u32(),
+            },
+            MessageId::Block => MessageRef::Block {
+                index: frame.get_u32(),
+                offset: frame.get_u32(),
+                data: frame,
+            },
+            MessageId::Cancel => MessageRef::Cancel {
+                index: frame.get_u32(),
+                offset: frame.get_u32(),
+                length: frame.get_u32(),
+            },
+            MessageId::Port => MessageRef::Port(frame.get_u16()),
+            MessageId::SuggestPiece => MessageRef::SuggestPiece {
+                index: frame.get_u32(),
+            },
+            MessageId::HaveAll => MessageRef::HaveAll,
+            MessageId::HaveNone => MessageRef::HaveNone,
+            MessageId::RejectRequest => MessageRef::RejectRequest {
+                index: frame.get_u32(),
+                offset: frame.get_u32(),
+                length: frame.get_u32(),
+            },
+            MessageId::AllowedFast => MessageRef::AllowedFast {
+                index: frame.get_u32(),
+            },
+            MessageId::Extended => MessageRef::Extended {
+                ext_id: frame.get_u8(),
+                payload: frame,
+            },
+        }
+    }
+
+    /// How many bytes this message takes on the wire, length prefix included.
+    pub fn encoded_len(&self) -> usize {
+        4 + match self {
+            MessageRef::KeepAlive => 0,
+            MessageRef::Choke
+            | MessageRef::Unchoke
+            | MessageRef::Interested
+            | MessageRef::Uninterested
+            | MessageRef::HaveAll
+            | MessageRef::HaveNone => 1,
+            MessageRef::Have(_)
+            | MessageRef::SuggestPiece { .. }
+            | MessageRef::AllowedFast { .. } => 5,
+            MessageRef::Bitfield(b) => 1 + b.as_bytes().len(),
+            MessageRef::Request { .. }
+            | MessageRef::Cancel { .. }
+            | MessageRef::RejectRequest { .. } => 13,
+            MessageRef::Block { data, .. } => 9 + data.len(),
+            MessageRef::Port(_) => 3,
+            MessageRef::Extended { payload, .. } => 2 + payload.len(),
+            MessageRef::Unknown { payload, .. } => 1 + payload.len(),
+        }
+    }
+
+    /// Copy the payload out into an owned [`Message`].
+    pub fn to_owned(self) -> Message {
+        self.to_message(Bytes::copy_from_slice)
+    }
+
+    /// Convert to a [`Message`], using `bytes` to turn borrowed payloads into owned ones.
+    pub(crate) fn to_message(self, bytes: impl Fn(&'a [u8]) -> Bytes) -> Message {
+        match self {
+            MessageRef::KeepAlive => Message::KeepAlive,
+            MessageRef::Choke => Message::Choke,
+            MessageRef::Unchoke => Message::Unchoke,
+            MessageRef::Interested => Message::Interested,
+            MessageRef::Uninterested => Message::Uninterested,
+            MessageRef::Have(index) => Message::Have(index),
+            MessageRef::Bitfield(b) => Message::Bitfield(b.into()),
+            MessageRef::Request {
+                index,
+                offset,
+                length,
+            } => Message::Request {
+                index,
+                offset,
+                length,
+            },
+            MessageRef::Cancel {
+                index,
+                offset,
+                length,
+            } => Message::Cancel {
+                index,
+                offset,
+                length,
+            },
+            MessageRef::Block {
+                index,
+                offset,
+                data,
+            } => Message::Block {
+                index,
+                offset,
+                data: bytes(data),
+            },
+            MessageRef::Port(port) => Message::Port(port),
+            MessageRef::SuggestPiece { index } => Message::SuggestPiece { index },
+            MessageRef::HaveAll => Message::HaveAll,
+            MessageRef::HaveNone => Message::HaveNone,
+            MessageRef::RejectRequest {
+                index,
+                offset,
+                length,
+            } => Message::RejectRequest {
+                index,
+                offset,
+                length,
+            },
+            MessageRef::AllowedFast { index } => Message::AllowedFast { index },
+            MessageRef::Extended { ext_id, payload } => Message::Extended {
+                ext_id,
+                payload: bytes(payload),
+            },
+            MessageRef::Unknown { id, payload } => Message::Unknown {
+                id,
+                payload: bytes(payload),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::Message;
+    use crate::pwp::MessageRef;
+    use crate::pwp::ProtocolError;
+    use bytes::Bytes;
+
+    #[test]
+    fn block_borrows_from_buffer() {
+        let buf = Message::Block {
+            index: 666,
+            offset: 420,
+            data: Bytes::from_static(b"hello!"),
+        }
+        .serialize();
+
+        let parsed = MessageRef::parse(&buf).unwrap().unwrap();
+
+        match parsed {
+            MessageRef::Block {
+                index: 666,
+                offset: 420,
+                data,
+            } => {
+                assert_eq!(data, b"hello!");
+                assert_eq!(data.as_ptr(), buf[13..].as_ptr());
+            }
+            other => panic!("expected a block, got {:?}", other),
+        }
+        assert_eq!(parsed.encoded_len(), buf.len());
+    }
+
+    #[test]
+    fn bitfield_borrows_from_buffer() {
+        let buf = [0x00, 0x00, 0x00, 0x03, 0x05, 0xA0, 0x01];
+
+        match MessageRef::parse(&buf).unwrap().unwrap() {
+            MessageRef::Bitfield(b) => {
+                assert_eq!(b.as_bytes().as_ptr(), buf[5..].as_ptr());
+                assert_eq!(b.iter_set_pieces().collect::<Vec<_>>(), vec![0, 2, 15]);
+            }
+            other => panic!("expected a bitfield, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_leaves_following_messages() {
+        let mut buf = Message::Have(7).serialize().to_vec();
+        buf.extend_from_slice(&Message::Interested.serialize());
+
+        let first = MessageRef::parse(&buf).unwrap().unwrap();
+        assert_eq!(first, MessageRef::Have(7));
+
+        let rest = &buf[first.encoded_len()..];
+        assert_eq!(
+            MessageRef::parse(rest).unwrap(),
+            Some(MessageRef::Interested)
+        );
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        let buf = Message::Have(7).serialize();
+
+        assert_eq!(MessageRef::parse(&buf[..8]), Ok(None));
+    }
+
+    #[test]
+    fn parse_checks_extensions() {
+        let buf = Message::HaveAll.serialize();
+
+        assert_eq!(
+            MessageRef::parse(&buf),
+            Err(ProtocolError::ExtensionNotNegotiated(0x0E))
+        );
+        assert_eq!(
+            MessageRef::parse_with(&buf, HandshakeFlags::FAST),
+            Ok(Some(MessageRef::HaveAll))
+        );
+    }
+
+    #[test]
+    fn to_owned_matches_deserialize() {
+        for msg in crate::pwp::tests::every_variant() {
+            let buf = msg.clone().serialize();
+            let parsed = MessageRef::parse_with(&buf, HandshakeFlags::all())
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(parsed.to_owned(), msg);
+            assert_eq!(parsed.encoded_len(), buf.len());
+        }
+    }
+}
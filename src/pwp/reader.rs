@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Splitting a byte stream into frames without tokio.
+
+use super::{default_max_frame_len, frame_len, HandshakeFlags, ProtocolError};
+use bytes::{Bytes, BytesMut};
+
+/// Accumulates bytes as they arrive and hands back whole length-prefixed frames.
+///
+/// This only splits frames apart; pass each one to [`Message::deserialize`](super::Message::deserialize) to parse it.
+#[derive(Debug)]
+pub struct FrameReader {
+    buf: BytesMut,
+    extensions: HandshakeFlags,
+    max_frame_len: u32,
+}
+
+impl FrameReader {
+    /// A reader for a connection that negotiated no extensions.
+    pub fn new() -> Self {
+        FrameReader::with_extensions(HandshakeFlags::empty())
+    }
+
+    /// A reader that accepts messages from the negotiated `extensions`.
+    pub fn with_extensions(extensions: HandshakeFlags) -> Self {
+        FrameReader {
+            buf: BytesMut::new(),
+            extensions,
+            max_frame_len: default_max_frame_len(extensions),
+        }
+    }
+
+    /// Refuse messages longer than `limit` bytes, not counting the length prefix.
+    ///
+    /// This starts at [`default_max_frame_len`](super::default_max_frame_len) for the negotiated
+    /// extensions.
+    pub fn max_frame_len(mut self, limit: u32) -> Self {
+        self.max_frame_len = limit;
+        self
+    }
+
+    /// Add bytes read from the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame, length prefix included, if one has arrived.
+    ///
+    /// The header is checked as soon as it arrives, so a bad ID or a length over the
+    /// [`max_frame_len`](FrameReader::max_frame_len) fails before we buffer its body.
+    /// After an error the stream can't be split any further, and the connection should be dropped.
+    pub fn next_frame(&mut self) -> Result<Option<Bytes>, ProtocolError> {
+        let len = frame_len(&self.buf, self.extensions)?;
+        if self.buf.len() >= 4
+            && u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]])
+                > self.max_frame_len
+        {
+            return Err(ProtocolError::PayloadTooLarge);
+        }
+        Ok(len.map(|len| self.buf.split_to(len).freeze()))
+    }
+
+    /// How many bytes are waiting for the rest of their frame.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        FrameReader::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::FrameReader;
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::Message;
+    use crate::pwp::ProtocolError;
+    use bytes::Bytes;
+
+    #[test]
+    fn prefix_split_across_pushes() {
+        let frame = Message::Have(23).serialize();
+        let mut reader = FrameReader::new();
+
+        reader.push(&frame[..2]);
+        assert_eq!(reader.next_frame(), Ok(None));
+        reader.push(&frame[2..6]);
+        assert_eq!(reader.next_frame(), Ok(None));
+        reader.push(&frame[6..]);
+
+        assert_eq!(reader.next_frame(), Ok(Some(frame)));
+        assert_eq!(reader.buffered_len(), 0);
+    }
+
+    #[test]
+    fn several_frames_in_one_push() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&Message::Unchoke.serialize());
+        data.extend_from_slice(&Message::KeepAlive.serialize());
+        data.extend_from_slice(&Message::Have(23).serialize());
+        let mut reader = FrameReader::new();
+
+        reader.push(&data[..data.len() - 1]);
+
+        assert_eq!(reader.next_frame(), Ok(Some(Message::Unchoke.serialize())));
+        assert_eq!(
+            reader.next_frame(),
+            Ok(Some(Message::KeepAlive.serialize()))
+        );
+        assert_eq!(reader.next_frame(), Ok(None));
+        assert_eq!(reader.buffered_len(), 8);
+
+        reader.push(&data[data.len() - 1..]);
+        let mut frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(
+            Message::deserialize(&mut frame),
+            Ok(Some(Message::Have(23)))
+        );
+    }
+
+    #[test]
+    fn oversized_prefix_fails_early() {
+        let mut reader = FrameReader::new();
+
+        reader.push(&[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        assert_eq!(reader.next_frame(), Err(ProtocolError::PayloadTooLarge));
+    }
+
+    #[test]
+    fn raised_frame_limit() {
+        let frame = Message::Block {
+            index: 0,
+            offset: 0,
+            data: Bytes::from(vec![0; 32 * 1024]),
+        }
+        .serialize();
+        let mut reader = FrameReader::new().max_frame_len(32 * 1024 + 9);
+
+        reader.push(&frame);
+
+        assert_eq!(reader.next_frame(), Ok(Some(frame)));
+    }
+
+    #[test]
+    fn invalid_id_fails_early() {
+        let mut reader = FrameReader::new();
+
+        reader.push(&[0x00, 0x00, 0x00, 0x03, 0x09]);
+
+        assert_eq!(
+            reader.next_frame(),
+            Err(ProtocolError::ExtensionNotNegotiated(0x09))
+        );
+    }
+
+    #[test]
+    fn negotiated_extension_is_accepted() {
+        let mut reader = FrameReader::with_extensions(HandshakeFlags::DHT);
+
+        reader.push(&Message::Port(6881).serialize());
+
+        assert_eq!(
+            reader.next_frame(),
+            Ok(Some(Message::Port(6881).serialize()))
+        );
+    }
+}
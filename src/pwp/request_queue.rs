@@ -0,0 +1,266 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Keeping a peer's request pipeline full.
+
+use super::Message;
+use crate::{BlockCoord, BlockOffset, BlockSize, PieceIndex};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+
+/// The blocks we want from one peer: those waiting to be requested, and those in flight.
+///
+/// At most `depth` requests are in flight at once.
+#[derive(Debug)]
+pub struct RequestQueue {
+    depth: usize,
+    waiting: VecDeque<(BlockCoord, BlockSize)>,
+    in_flight: HashMap<BlockCoord, BlockSize>,
+}
+
+impl RequestQueue {
+    /// A queue that keeps up to `depth` requests in flight.
+    pub fn new(depth: usize) -> Self {
+        RequestQueue {
+            depth,
+            waiting: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Change how many requests may be in flight. Requests already sent aren't affected.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    /// The number of requests sent but not yet answered.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// The number of blocks waiting for room in the pipeline.
+    pub fn waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Add a block to the end of the queue.
+    pub fn enqueue(&mut self, index: PieceIndex, offset: BlockOffset, length: BlockSize) {
+        self.waiting
+            .push_back((BlockCoord::new(index, offset), length));
+    }
+
+    /// Move waiting blocks into flight until the pipeline is full, returning the requests to send.
+    pub fn next_requests(&mut self) -> Vec<Message> {
+        let room = self.depth.saturating_sub(self.in_flight.len());
+        let mut requests = Vec::with_capacity(room.min(self.waiting.len()));
+
+        while self.in_flight.len() < self.depth {
+            let (coord, length) = match self.waiting.pop_front() {
+                Some(next) => next,
+                None => break,
+            };
+            if let Entry::Vacant(entry) = self.in_flight.entry(coord) {
+                entry.insert(length);
+                requests.push(Message::Request {
+                    index: coord.index,
+                    offset: coord.offset,
+                    length,
+                });
+            }
+        }
+
+        requests
+    }
+
+    /// Match a received block against the requests in flight.
+    ///
+    /// Returns whether we were waiting on it; a block we never asked for, or one whose length
+    /// doesn't match our request, is unexpected.
+    pub fn on_block_received(
+        &mut self,
+        index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockSize,
+    ) -> bool {
+        self.take_in_flight(index, offset, length)
+    }
+
+    /// Handle the peer rejecting one of our requests (Fast Extension).
+    ///
+    /// Returns whether the request was in flight. It is not retried; enqueue it again to do that.
+    pub fn on_reject(&mut self, index: PieceIndex, offset: BlockOffset, length: BlockSize) -> bool {
+        self.take_in_flight(index, offset, length)
+    }
+
+    /// Handle the peer choking us, which without the Fast Extension discards our requests.
+    ///
+    /// Every request in flight is dropped and returned, in order, so the blocks can be
+    /// enqueued again here or with another peer. Waiting blocks are kept.
+    pub fn on_choke(&mut self) -> Vec<(BlockCoord, BlockSize)> {
+        self.clear_in_flight()
+    }
+
+    /// Forget every request in flight, returning them in order.
+    pub fn clear_in_flight(&mut self) -> Vec<(BlockCoord, BlockSize)> {
+        let mut dropped: Vec<_> = self.in_flight.drain().collect();
+        dropped.sort_unstable();
+        dropped
+    }
+
+    fn take_in_flight(
+        &mut self,
+        index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockSize,
+    ) -> bool {
+        let coord = BlockCoord::new(index, offset);
+        match self.in_flight.get(&coord) {
+            Some(expected) if *expected == length => {
+                self.in_flight.remove(&coord);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::Message;
+    use crate::pwp::RequestQueue;
+    use crate::BlockCoord;
+
+    fn queue_of(depth: usize, blocks: u32) -> RequestQueue {
+        let mut queue = RequestQueue::new(depth);
+        for i in 0..blocks {
+            queue.enqueue(0, i * 16384, 16384);
+        }
+        queue
+    }
+
+    #[test]
+    fn caps_requests_in_flight() {
+        let mut queue = queue_of(2, 5);
+
+        let requests = queue.next_requests();
+
+        assert_eq!(
+            requests,
+            vec![
+                Message::Request {
+                    index: 0,
+                    offset: 0,
+                    length: 16384
+                },
+                Message::Request {
+                    index: 0,
+                    offset: 16384,
+                    length: 16384
+                },
+            ]
+        );
+        assert_eq!(queue.in_flight(), 2);
+        assert_eq!(queue.waiting(), 3);
+        assert!(queue.next_requests().is_empty());
+    }
+
+    #[test]
+    fn block_frees_a_slot() {
+        let mut queue = queue_of(2, 5);
+        queue.next_requests();
+
+        assert!(queue.on_block_received(0, 16384, 16384));
+        assert_eq!(queue.in_flight(), 1);
+        assert_eq!(
+            queue.next_requests(),
+            vec![Message::Request {
+                index: 0,
+                offset: 32768,
+                length: 16384
+            }]
+        );
+    }
+
+    #[test]
+    fn unexpected_blocks() {
+        let mut queue = queue_of(2, 5);
+        queue.next_requests();
+
+        assert!(!queue.on_block_received(0, 32768, 16384));
+        assert!(!queue.on_block_received(0, 0, 100));
+        assert!(queue.on_block_received(0, 0, 16384));
+        assert!(!queue.on_block_received(0, 0, 16384));
+    }
+
+    #[test]
+    fn reject_removes_request() {
+        let mut queue = queue_of(1, 1);
+        queue.next_requests();
+
+        assert!(queue.on_reject(0, 0, 16384));
+        assert_eq!(queue.in_flight(), 0);
+        assert!(!queue.on_reject(0, 0, 16384));
+    }
+
+    #[test]
+    fn choke_returns_requests_in_flight() {
+        let mut queue = queue_of(2, 3);
+        queue.next_requests();
+
+        assert_eq!(
+            queue.on_choke(),
+            vec![
+                (BlockCoord::new(0, 0), 16384),
+                (BlockCoord::new(0, 16384), 16384)
+            ]
+        );
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(queue.waiting(), 1);
+        assert!(!queue.on_block_received(0, 0, 16384));
+    }
+
+    #[test]
+    fn choked_blocks_can_be_requeued() {
+        let mut queue = queue_of(1, 1);
+        queue.next_requests();
+
+        for (coord, length) in queue.clear_in_flight() {
+            queue.enqueue(coord.index, coord.offset, length);
+        }
+
+        assert_eq!(
+            queue.next_requests(),
+            vec![Message::Request {
+                index: 0,
+                offset: 0,
+                length: 16384
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_enqueue_keeps_first_length() {
+        let mut queue = RequestQueue::new(4);
+        queue.enqueue(1, 0, 16384);
+        queue.enqueue(1, 0, 100);
+
+        assert_eq!(queue.next_requests().len(), 1);
+        assert!(!queue.on_block_received(1, 0, 100));
+        assert!(queue.on_block_received(1, 0, 16384));
+    }
+
+    #[test]
+    fn duplicate_enqueue_is_requested_once() {
+        let mut queue = RequestQueue::new(4);
+        queue.enqueue(1, 0, 16384);
+        queue.enqueue(1, 0, 16384);
+
+        assert_eq!(queue.next_requests().len(), 1);
+        assert_eq!(queue.waiting(), 0);
+    }
+}
@@ -0,0 +1,397 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! A connection to one peer, from the handshake on, over any transport.
+
+use super::{CodecError, Connection, Handshake, HandshakeFlags, Message, ProtocolError, PwpCodec};
+use crate::{InfoHash, PeerId};
+use bytes::BytesMut;
+use std::io;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A reliable, ordered byte stream to a peer.
+///
+/// Anything that's `AsyncRead + AsyncWrite + Unpin` qualifies, so a `TcpStream` works as-is,
+/// and so does a uTP socket or a WebRTC data channel wrapped in those traits.
+pub trait PeerTransport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> PeerTransport for T {}
+
+/// A peer we've exchanged handshakes with, and the state of our connection to them.
+///
+/// Every message sent or received goes through the [`Connection`],
+/// so its choke and interest flags and transfer counters stay current.
+#[derive(Debug)]
+pub struct PeerSession<T> {
+    transport: T,
+    codec: PwpCodec,
+    extensions: HandshakeFlags,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    conn: Connection,
+    peer: Handshake,
+    availability: Option<Message>,
+}
+
+/// Errors from [`connect`].
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error(transparent)]
+    Handshake(#[from] ProtocolError),
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("peer closed the connection before saying which pieces it has")]
+    Closed,
+    /// The peer's first message wasn't a `Bitfield`, `HaveAll` or `HaveNone`; this is its ID.
+    #[error("peer sent message id {0} before saying which pieces it has")]
+    MissingAvailability(u8),
+}
+
+/// Open a session with a peer for the torrent with `info_hash`, ready to exchange pieces.
+///
+/// This exchanges handshakes, checks the peer is serving the same torrent and isn't us,
+/// and negotiates extensions. If both sides support the Fast Extension, the peer has to open
+/// with a `Bitfield`, `HaveAll` or `HaveNone`, which is read and kept as
+/// [`PeerSession::peer_availability`]. Otherwise a bitfield is optional, so none is waited for.
+pub async fn connect<T: PeerTransport>(
+    transport: T,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    flags: HandshakeFlags,
+) -> Result<PeerSession<T>, ConnectError> {
+    let mut session =
+        PeerSession::open(transport, Handshake::new(info_hash, peer_id, flags)).await?;
+    session.peer.matches(&info_hash, None)?;
+    if session.peer.is_self_connection(&peer_id) {
+        return Err(ConnectError::SelfConnection);
+    }
+
+    if session.extensions.supports_fast() {
+        loop {
+            match session.recv().await?.ok_or(ConnectError::Closed)? {
+                Message::KeepAlive => {}
+                msg @ (Message::Bitfield(_) | Message::HaveAll | Message::HaveNone) => {
+                    session.availability = Some(msg);
+                    break;
+                }
+                other => {
+                    return Err(ConnectError::MissingAvailability(
+                        other.id_byte().expect("only keep-alives have no ID"),
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(session)
+}
+
+impl<T: PeerTransport> PeerSession<T> {
+    /// Exchange handshakes over `transport` and start a session with the extensions both sides support.
+    ///
+    /// The peer's handshake is accepted as-is; check it with [`Handshake::matches`].
+    pub async fn open(mut transport: T, ours: Handshake) -> Result<Self, CodecError> {
+        let flags = ours.flags();
+        let peer = ours.exchange(&mut transport).await?;
+        let extensions = flags.negotiate(&peer.flags());
+
+        Ok(PeerSession {
+            transport,
+            codec: PwpCodec::with_extensions(extensions),
+            extensions,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            conn: Connection::default(),
+            peer,
+            availability: None,
+        })
+    }
+
+    /// The handshake the peer sent us.
+    pub fn peer_handshake(&self) -> &Handshake {
+        &self.peer
+    }
+
+    /// The extensions both sides support.
+    pub fn extensions(&self) -> HandshakeFlags {
+        self.extensions
+    }
+
+    /// The `Bitfield`, `HaveAll` or `HaveNone` the peer opened with, if [`connect`] read one.
+    pub fn peer_availability(&self) -> Option<&Message> {
+        self.availability.as_ref()
+    }
+
+    /// Our state of the connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Our state of the connection, for building messages like `set_interested` does.
+    pub fn connection_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    /// Send a message and flush it.
+    ///
+    /// The message only counts towards [`Connection::bytes_uploaded`] once it's been written.
+    pub async fn send(&mut self, msg: Message) -> Result<(), CodecError> {
+        self.codec.encode(msg.clone(), &mut self.write_buf)?;
+        let written = self.transport.write_all(&self.write_buf).await;
+        self.write_buf.clear();
+        written?;
+        self.transport.flush().await?;
+        self.conn.record_sent(&msg, Instant::now());
+        Ok(())
+    }
+
+    /// Wait for the next message, or `None` once the peer closes the connection between messages.
+    pub async fn recv(&mut self) -> Result<Option<Message>, CodecError> {
+        loop {
+            if let Some(msg) = self.codec.decode(&mut self.read_buf)? {
+                self.conn.apply_incoming(&msg)?;
+                return Ok(Some(msg));
+            }
+
+            if self.transport.read_buf(&mut self.read_buf).await? == 0 {
+                return if self.read_buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+                };
+            }
+        }
+    }
+
+    /// The underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Close the session, returning the transport. Any buffered bytes are discarded.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::ChokeFlag;
+    use crate::pwp::CodecError;
+    use crate::pwp::Handshake;
+    use crate::pwp::HandshakeFlags;
+    use crate::pwp::InterestFlag;
+    use crate::pwp::Message;
+    use crate::pwp::PeerSession;
+    use crate::pwp::ProtocolError;
+    use bytes::Bytes;
+    use std::io::{self, Cursor};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// A transport that replays canned bytes from the peer and records what we write.
+    struct MockTransport {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+        /// Fail every write, like a connection the peer has reset.
+        fail_writes: bool,
+    }
+
+    impl MockTransport {
+        fn new(incoming: Vec<u8>) -> Self {
+            MockTransport {
+                incoming: Cursor::new(incoming),
+                outgoing: Vec::new(),
+                fail_writes: false,
+            }
+        }
+    }
+
+    impl AsyncRead for MockTransport {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.incoming).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MockTransport {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.fail_writes {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            Pin::new(&mut self.outgoing).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.outgoing).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.outgoing).poll_shutdown(cx)
+        }
+    }
+
+    fn handshakes() -> (Handshake, Handshake) {
+        let ours = Handshake::new([7; 20], *b"-LS0001-ours00000000", HandshakeFlags::FAST);
+        let theirs = Handshake::new(
+            [7; 20],
+            *b"-XX0001-theirs000000",
+            HandshakeFlags::FAST | HandshakeFlags::DHT,
+        );
+        (ours, theirs)
+    }
+
+    #[tokio::test]
+    async fn drive_session_over_mock_transport() {
+        let (ours, theirs) = handshakes();
+        let mut incoming = theirs.serialize().to_vec();
+        incoming.extend_from_slice(&Message::Unchoke.serialize());
+        incoming.extend_from_slice(&Message::HaveAll.serialize());
+
+        let mut session = PeerSession::open(MockTransport::new(incoming), ours.clone())
+            .await
+            .unwrap();
+        assert_eq!(session.peer_handshake(), &theirs);
+
+        assert_eq!(session.recv().await.unwrap(), Some(Message::Unchoke));
+        assert_eq!(session.connection().them_choking(), ChokeFlag::Unchoked);
+        assert_eq!(session.recv().await.unwrap(), Some(Message::HaveAll));
+        assert_eq!(session.recv().await.unwrap(), None);
+
+        let interested = session.connection_mut().set_interested();
+        session.send(interested).await.unwrap();
+        assert_eq!(
+            session.connection().us_interested(),
+            InterestFlag::Interested
+        );
+        assert_eq!(session.connection().bytes_uploaded(), 5);
+        assert_eq!(session.connection().bytes_downloaded(), 10);
+
+        let mut expected = ours.serialize().to_vec();
+        expected.extend_from_slice(&Message::Interested.serialize());
+        assert_eq!(session.into_inner().outgoing, expected);
+    }
+
+    #[tokio::test]
+    async fn sent_state_messages_update_connection() {
+        let (ours, theirs) = handshakes();
+        let mut incoming = theirs.serialize().to_vec();
+        incoming.extend_from_slice(&Message::Unchoke.serialize());
+        let mut session = PeerSession::open(MockTransport::new(incoming), ours)
+            .await
+            .unwrap();
+        session.recv().await.unwrap();
+
+        session.send(Message::Interested).await.unwrap();
+        session.send(Message::Unchoke).await.unwrap();
+
+        assert!(session.connection().can_request());
+        assert_eq!(session.connection().us_choking(), ChokeFlag::Unchoked);
+
+        session.send(Message::Uninterested).await.unwrap();
+        session.send(Message::Choke).await.unwrap();
+
+        assert!(!session.connection().can_request());
+        assert_eq!(session.connection().us_choking(), ChokeFlag::Choked);
+    }
+
+    #[tokio::test]
+    async fn unencodable_message_is_not_counted() {
+        let (ours, theirs) = handshakes();
+        let mut session = PeerSession::open(
+            MockTransport::new(theirs.serialize().to_vec()),
+            ours.clone(),
+        )
+        .await
+        .unwrap();
+
+        let bogus = Message::Unknown {
+            id: 0x04,
+            payload: Bytes::from_static(&[0, 0, 0, 1]),
+        };
+        assert!(matches!(
+            session.send(bogus).await,
+            Err(CodecError::Encode(_))
+        ));
+        assert_eq!(session.connection().bytes_uploaded(), 0);
+
+        session.send(Message::Interested).await.unwrap();
+        let mut expected = ours.serialize().to_vec();
+        expected.extend_from_slice(&Message::Interested.serialize());
+        assert_eq!(session.into_inner().outgoing, expected);
+    }
+
+    #[tokio::test]
+    async fn failed_write_is_not_counted_or_resent() {
+        let (ours, theirs) = handshakes();
+        let mut session = PeerSession::open(
+            MockTransport::new(theirs.serialize().to_vec()),
+            ours.clone(),
+        )
+        .await
+        .unwrap();
+
+        session.transport.fail_writes = true;
+        assert!(matches!(
+            session.send(Message::Have(1)).await,
+            Err(CodecError::Io(e)) if e.kind() == io::ErrorKind::ConnectionReset
+        ));
+        assert_eq!(session.connection().bytes_uploaded(), 0);
+
+        session.transport.fail_writes = false;
+        session.send(Message::Interested).await.unwrap();
+        assert_eq!(session.connection().bytes_uploaded(), 5);
+        let mut expected = ours.serialize().to_vec();
+        expected.extend_from_slice(&Message::Interested.serialize());
+        assert_eq!(session.into_inner().outgoing, expected);
+    }
+
+    #[tokio::test]
+    async fn unnegotiated_extension_is_rejected() {
+        let (_, theirs) = handshakes();
+        let ours = Handshake::new([7; 20], *b"-LS0001-ours00000000", HandshakeFlags::empty());
+        let mut incoming = theirs.serialize().to_vec();
+        incoming.extend_from_slice(&Message::HaveAll.serialize());
+
+        let mut session = PeerSession::open(MockTransport::new(incoming), ours)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            session.recv().await,
+            Err(CodecError::Protocol(ProtocolError::ExtensionNotNegotiated(
+                0x0E
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn closed_mid_message() {
+        let (ours, theirs) = handshakes();
+        let mut incoming = theirs.serialize().to_vec();
+        incoming.extend_from_slice(&Message::Have(1).serialize()[..6]);
+
+        let mut session = PeerSession::open(MockTransport::new(incoming), ours)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            session.recv().await,
+            Err(CodecError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -0,0 +1,374 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Fetching a torrent's info dictionary from peers (BEP 9).
+
+use super::{Message, ProtocolError};
+use crate::bencode::{self, BencodeValue as Value};
+use crate::piece::verify_piece;
+use crate::InfoHash;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// The name this extension goes by in the extended handshake's `m` dictionary.
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The size of every metadata piece but the last.
+pub const METADATA_PIECE_LEN: u32 = 16 * 1024;
+
+/// The largest info dictionary we're willing to fetch.
+pub const MAX_METADATA_SIZE: u32 = 16 * 1024 * 1024;
+
+/// A `ut_metadata` message, carried in the payload of `Message::Extended`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    /// Ask for a piece of the metadata.
+    Request { piece: u32 },
+    /// A piece of the metadata, along with the size of the whole thing.
+    Data {
+        piece: u32,
+        total_size: u32,
+        data: Bytes,
+    },
+    /// The peer won't send us the piece we asked for.
+    Reject { piece: u32 },
+}
+
+impl MetadataMessage {
+    /// Wrap this in a message for a peer that wants `ut_metadata` on `ext_id`.
+    pub fn to_message(&self, ext_id: u8) -> Message {
+        Message::Extended {
+            ext_id,
+            payload: self.to_payload(),
+        }
+    }
+
+    /// Bencode this message, followed by the piece's data if it has any.
+    pub fn to_payload(&self) -> Bytes {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"msg_type".to_vec(), Value::Integer(msg_type));
+        dict.insert(b"piece".to_vec(), Value::Integer(*piece as i64));
+
+        match self {
+            MetadataMessage::Data {
+                total_size, data, ..
+            } => {
+                dict.insert(b"total_size".to_vec(), Value::Integer(*total_size as i64));
+                let header = bencode::encode(&Value::Dict(dict));
+                let mut buf = BytesMut::with_capacity(header.len() + data.len());
+                buf.put(header);
+                buf.put_slice(data);
+                buf.freeze()
+            }
+            _ => bencode::encode(&Value::Dict(dict)),
+        }
+    }
+
+    /// Parse the payload of an extended message sent on our `ut_metadata` ID.
+    pub fn from_payload(payload: &Bytes) -> Result<Self, ProtocolError> {
+        let (dict, header_len) = match bencode::decode_prefix(payload) {
+            Ok((Value::Dict(dict), len)) => (dict, len),
+            _ => return Err(ProtocolError::InvalidMetadataMessage),
+        };
+
+        let int = |key: &[u8]| match dict.get(key) {
+            Some(Value::Integer(i)) => u32::try_from(*i).ok(),
+            _ => None,
+        };
+        let msg_type = int(b"msg_type").ok_or(ProtocolError::InvalidMetadataMessage)?;
+        let piece = int(b"piece").ok_or(ProtocolError::InvalidMetadataMessage)?;
+        let has_data = header_len < payload.len();
+
+        match (msg_type, has_data) {
+            (0, false) => Ok(MetadataMessage::Request { piece }),
+            (1, _) => Ok(MetadataMessage::Data {
+                piece,
+                total_size: int(b"total_size").ok_or(ProtocolError::InvalidMetadataMessage)?,
+                data: payload.slice(header_len..),
+            }),
+            (2, false) => Ok(MetadataMessage::Reject { piece }),
+            _ => Err(ProtocolError::InvalidMetadataMessage),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MetadataError {
+    #[error("metadata size {0} is zero or larger than we're willing to fetch")]
+    InvalidSize(u32),
+    #[error("piece {piece} is out of range for {piece_count} metadata pieces")]
+    PieceOutOfRange { piece: u32, piece_count: u32 },
+    #[error("piece {piece} should be {expected} bytes but was {actual}")]
+    WrongPieceLength {
+        piece: u32,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("metadata pieces are still missing")]
+    Incomplete,
+    #[error("metadata doesn't match the info hash")]
+    HashMismatch,
+}
+
+/// Collects metadata pieces and checks the finished info dictionary against its info hash.
+#[derive(Debug)]
+pub struct MetadataAssembler {
+    info_hash: InfoHash,
+    total_size: u32,
+    pieces: Vec<Option<Bytes>>,
+}
+
+impl MetadataAssembler {
+    /// Start collecting `total_size` bytes of metadata, as announced in a peer's extended handshake.
+    pub fn new(info_hash: InfoHash, total_size: u32) -> Result<Self, MetadataError> {
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            return Err(MetadataError::InvalidSize(total_size));
+        }
+        let piece_count = (total_size - 1) / METADATA_PIECE_LEN + 1;
+        Ok(MetadataAssembler {
+            info_hash,
+            total_size,
+            pieces: vec![None; piece_count as usize],
+        })
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    /// The pieces we still need to request.
+    pub fn missing_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_none())
+            .map(|(i, _)| i as u32)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Store one piece of the metadata.
+    ///
+    /// Every piece must be exactly `METADATA_PIECE_LEN` bytes, except the last, which holds the remainder.
+    pub fn add_piece(&mut self, piece: u32, data: Bytes) -> Result<(), MetadataError> {
+        let piece_count = self.piece_count();
+        if piece >= piece_count {
+            return Err(MetadataError::PieceOutOfRange { piece, piece_count });
+        }
+
+        let expected = if piece == piece_count - 1 {
+            self.total_size - piece * METADATA_PIECE_LEN
+        } else {
+            METADATA_PIECE_LEN
+        } as usize;
+        if data.len() != expected {
+            return Err(MetadataError::WrongPieceLength {
+                piece,
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        self.pieces[piece as usize] = Some(data);
+        Ok(())
+    }
+
+    /// Join the pieces into the info dictionary, verifying it against the info hash.
+    pub fn finish(self) -> Result<Bytes, MetadataError> {
+        let mut buf = BytesMut::with_capacity(self.total_size as usize);
+        for piece in self.pieces {
+            buf.put(piece.ok_or(MetadataError::Incomplete)?);
+        }
+
+        if !verify_piece(&buf, &self.info_hash) {
+            return Err(MetadataError::HashMismatch);
+        }
+
+        Ok(buf.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwp::Message;
+    use crate::pwp::MetadataAssembler;
+    use crate::pwp::MetadataError;
+    use crate::pwp::MetadataMessage;
+    use crate::pwp::ProtocolError;
+    use crate::pwp::METADATA_PIECE_LEN;
+    use bytes::Bytes;
+    use sha1::{Digest, Sha1};
+
+    fn info_dict(len: usize) -> Bytes {
+        (0..len)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>()
+            .into()
+    }
+
+    fn info_hash(info: &[u8]) -> [u8; 20] {
+        Sha1::digest(info).into()
+    }
+
+    /// Answer a request the way a seeding peer would.
+    fn serve(info: &Bytes, msg: Message) -> Message {
+        let payload = match msg {
+            Message::Extended { ext_id: 3, payload } => payload,
+            other => panic!("expected a ut_metadata message, got {:?}", other),
+        };
+        let piece = match MetadataMessage::from_payload(&payload).unwrap() {
+            MetadataMessage::Request { piece } => piece,
+            other => panic!("expected a request, got {:?}", other),
+        };
+        let start = (piece * METADATA_PIECE_LEN) as usize;
+        let end = (start + METADATA_PIECE_LEN as usize).min(info.len());
+        MetadataMessage::Data {
+            piece,
+            total_size: info.len() as u32,
+            data: info.slice(start..end),
+        }
+        .to_message(2)
+    }
+
+    #[test]
+    fn fetch_multi_piece_metadata() {
+        let info = info_dict(40_000);
+        let mut assembler = MetadataAssembler::new(info_hash(&info), 40_000).unwrap();
+        assert_eq!(assembler.piece_count(), 3);
+
+        let requests: Vec<u32> = assembler.missing_pieces().collect();
+        for piece in requests {
+            let response = serve(&info, MetadataMessage::Request { piece }.to_message(3));
+            let payload = match response {
+                Message::Extended { ext_id: 2, payload } => payload,
+                other => panic!("expected a ut_metadata message, got {:?}", other),
+            };
+            match MetadataMessage::from_payload(&payload).unwrap() {
+                MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data,
+                } => {
+                    assert_eq!(total_size, 40_000);
+                    assembler.add_piece(piece, data).unwrap();
+                }
+                other => panic!("expected data, got {:?}", other),
+            }
+        }
+
+        assert!(assembler.is_complete());
+        assert_eq!(assembler.finish(), Ok(info));
+    }
+
+    #[test]
+    fn parse_reject() {
+        let payload = Bytes::from_static(b"d8:msg_typei2e5:piecei4ee");
+
+        assert_eq!(
+            MetadataMessage::from_payload(&payload),
+            Ok(MetadataMessage::Reject { piece: 4 })
+        );
+        assert_eq!(MetadataMessage::Reject { piece: 4 }.to_payload(), payload);
+    }
+
+    #[test]
+    fn encode_request() {
+        assert_eq!(
+            &MetadataMessage::Request { piece: 0 }.to_payload()[..],
+            b"d8:msg_typei0e5:piecei0ee"
+        );
+    }
+
+    #[test]
+    fn parse_data_keeps_trailing_bytes() {
+        let payload = Bytes::from_static(b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc");
+
+        assert_eq!(
+            MetadataMessage::from_payload(&payload),
+            Ok(MetadataMessage::Data {
+                piece: 0,
+                total_size: 3,
+                data: Bytes::from_static(b"abc"),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_metadata_messages() {
+        for payload in [
+            &b"le"[..],
+            b"d8:msg_typei9e5:piecei0ee",
+            b"d8:msg_typei0ee",
+            b"d8:msg_typei0e5:piecei0eeextra",
+            b"d8:msg_typei1e5:piecei0ee",
+        ] {
+            assert_eq!(
+                MetadataMessage::from_payload(&Bytes::copy_from_slice(payload)),
+                Err(ProtocolError::InvalidMetadataMessage)
+            );
+        }
+    }
+
+    #[test]
+    fn finish_rejects_hash_mismatch() {
+        let info = info_dict(100);
+        let mut assembler = MetadataAssembler::new([0; 20], 100).unwrap();
+        assembler.add_piece(0, info).unwrap();
+
+        assert_eq!(assembler.finish(), Err(MetadataError::HashMismatch));
+    }
+
+    #[test]
+    fn finish_rejects_missing_pieces() {
+        let info = info_dict(20_000);
+        let mut assembler = MetadataAssembler::new(info_hash(&info), 20_000).unwrap();
+        assembler.add_piece(1, info.slice(16_384..)).unwrap();
+
+        assert_eq!(assembler.missing_pieces().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(assembler.finish(), Err(MetadataError::Incomplete));
+    }
+
+    #[test]
+    fn add_piece_checks_length_and_range() {
+        let mut assembler = MetadataAssembler::new([0; 20], 20_000).unwrap();
+
+        assert_eq!(
+            assembler.add_piece(0, info_dict(100)),
+            Err(MetadataError::WrongPieceLength {
+                piece: 0,
+                expected: 16_384,
+                actual: 100
+            })
+        );
+        assert_eq!(
+            assembler.add_piece(2, info_dict(100)),
+            Err(MetadataError::PieceOutOfRange {
+                piece: 2,
+                piece_count: 2
+            })
+        );
+    }
+
+    #[test]
+    fn new_rejects_bad_sizes() {
+        assert_eq!(
+            MetadataAssembler::new([0; 20], 0).unwrap_err(),
+            MetadataError::InvalidSize(0)
+        );
+        assert_eq!(
+            MetadataAssembler::new([0; 20], u32::MAX).unwrap_err(),
+            MetadataError::InvalidSize(u32::MAX)
+        );
+    }
+}
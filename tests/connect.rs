@@ -0,0 +1,148 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! Connecting to a scripted peer with `pwp::connect`.
+
+#![cfg(feature = "tokio")]
+
+use landslide::pwp::{
+    connect, Bitfield, ChokeFlag, ConnectError, Handshake, HandshakeFlags, Message, MessageId,
+    ProtocolError,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+const INFO_HASH: [u8; 20] = *b"landslide-info-hash!";
+const OUR_ID: [u8; 20] = *b"-LS0001-leecher00000";
+const THEIR_ID: [u8; 20] = *b"-XX0001-scripted0000";
+
+/// Play the peer's side: read our handshake, then send back `handshake` and `messages` verbatim.
+async fn scripted_peer(mut stream: DuplexStream, handshake: Handshake, messages: Vec<Message>) {
+    let mut ours = [0; 68];
+    stream.read_exact(&mut ours).await.unwrap();
+    assert_eq!(
+        Handshake::deserialize(&ours).unwrap().info_hash(),
+        &INFO_HASH
+    );
+
+    let mut script = handshake.serialize().to_vec();
+    for msg in messages {
+        script.extend_from_slice(&msg.serialize());
+    }
+    stream.write_all(&script).await.unwrap();
+}
+
+#[tokio::test]
+async fn connect_reads_opening_bitfield() {
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let mut bitfield = Bitfield::new(12);
+    bitfield.set(3).unwrap();
+    let peer = scripted_peer(
+        theirs,
+        Handshake::new(INFO_HASH, THEIR_ID, HandshakeFlags::FAST),
+        vec![
+            Message::KeepAlive,
+            Message::Bitfield(bitfield.clone()),
+            Message::Unchoke,
+        ],
+    );
+
+    let (session, ()) = tokio::join!(
+        connect(
+            ours,
+            INFO_HASH,
+            OUR_ID,
+            HandshakeFlags::FAST | HandshakeFlags::DHT
+        ),
+        peer
+    );
+    let mut session = session.unwrap();
+
+    assert_eq!(session.extensions(), HandshakeFlags::FAST);
+    assert_eq!(session.peer_handshake().peer_id(), &THEIR_ID);
+    assert!(matches!(
+        session.peer_availability(),
+        Some(Message::Bitfield(b)) if b.as_bytes() == bitfield.as_bytes()
+    ));
+
+    assert_eq!(session.recv().await.unwrap(), Some(Message::Unchoke));
+    assert_eq!(session.connection().them_choking(), ChokeFlag::Unchoked);
+}
+
+#[tokio::test]
+async fn connect_without_fast_waits_for_nothing() {
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let peer = scripted_peer(
+        theirs,
+        Handshake::new(INFO_HASH, THEIR_ID, HandshakeFlags::empty()),
+        vec![],
+    );
+
+    let (session, ()) = tokio::join!(connect(ours, INFO_HASH, OUR_ID, HandshakeFlags::FAST), peer);
+    let session = session.unwrap();
+
+    assert_eq!(session.extensions(), HandshakeFlags::empty());
+    assert!(session.peer_availability().is_none());
+}
+
+#[tokio::test]
+async fn connect_rejects_other_torrent() {
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let peer = scripted_peer(
+        theirs,
+        Handshake::new(*b"some-other-torrent!!", THEIR_ID, HandshakeFlags::FAST),
+        vec![Message::HaveAll],
+    );
+
+    let (result, ()) = tokio::join!(connect(ours, INFO_HASH, OUR_ID, HandshakeFlags::FAST), peer);
+
+    assert!(matches!(
+        result,
+        Err(ConnectError::Handshake(ProtocolError::InfoHashMismatch))
+    ));
+}
+
+#[tokio::test]
+async fn connect_rejects_ourselves() {
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let peer = scripted_peer(
+        theirs,
+        Handshake::new(INFO_HASH, OUR_ID, HandshakeFlags::FAST),
+        vec![Message::HaveAll],
+    );
+
+    let (result, ()) = tokio::join!(connect(ours, INFO_HASH, OUR_ID, HandshakeFlags::FAST), peer);
+
+    assert!(matches!(result, Err(ConnectError::SelfConnection)));
+}
+
+#[tokio::test]
+async fn connect_requires_availability_with_fast() {
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let peer = scripted_peer(
+        theirs,
+        Handshake::new(INFO_HASH, THEIR_ID, HandshakeFlags::FAST),
+        vec![Message::Unchoke],
+    );
+
+    let (result, ()) = tokio::join!(connect(ours, INFO_HASH, OUR_ID, HandshakeFlags::FAST), peer);
+
+    assert!(matches!(
+        result,
+        Err(ConnectError::MissingAvailability(id)) if id == MessageId::Unchoke as u8
+    ));
+}
+
+#[tokio::test]
+async fn connect_notices_peer_hanging_up() {
+    let (ours, theirs) = tokio::io::duplex(4096);
+    let peer = scripted_peer(
+        theirs,
+        Handshake::new(INFO_HASH, THEIR_ID, HandshakeFlags::FAST),
+        vec![],
+    );
+
+    let (result, ()) = tokio::join!(connect(ours, INFO_HASH, OUR_ID, HandshakeFlags::FAST), peer);
+
+    assert!(matches!(result, Err(ConnectError::Closed)));
+}
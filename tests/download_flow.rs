@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2021 Rosa Richter
+//
+// SPDX-License-Identifier: MIT
+
+//! A leecher downloading a whole torrent from a seeder over an in-memory stream,
+//! from the handshake through to verified pieces.
+
+#![cfg(all(feature = "tokio", feature = "sha1"))]
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use landslide::pwp::{
+    Bitfield, Connection, Handshake, HandshakeFlags, Message, PieceAssembler, PwpCodec,
+    DEFAULT_MAX_BLOCK_SIZE,
+};
+use sha1::{Digest, Sha1};
+use tokio::io::DuplexStream;
+use tokio_util::codec::Framed;
+
+const PIECE_LEN: u32 = 32 * 1024;
+const TOTAL_LEN: u64 = 80 * 1024;
+const INFO_HASH: [u8; 20] = *b"landslide-info-hash!";
+
+struct Torrent {
+    data: Bytes,
+    hashes: Vec<[u8; 20]>,
+}
+
+impl Torrent {
+    fn generate() -> Self {
+        let data: Bytes = (0..TOTAL_LEN).map(|i| (i * 7 % 256) as u8).collect();
+        let hashes = data
+            .chunks(PIECE_LEN as usize)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        Torrent { data, hashes }
+    }
+
+    fn piece_count(&self) -> u32 {
+        self.hashes.len() as u32
+    }
+}
+
+async fn seed(mut stream: DuplexStream, torrent: &Torrent) {
+    let us = Handshake::new(INFO_HASH, *b"-LS0001-seeder000000", HandshakeFlags::FAST);
+    let them = us.clone().exchange(&mut stream).await.unwrap();
+    assert_eq!(them.info_hash(), &INFO_HASH);
+
+    let extensions = us.flags().negotiate(&them.flags());
+    let mut framed = Framed::new(stream, PwpCodec::with_extensions(extensions));
+
+    let mut have = Bitfield::new(torrent.piece_count());
+    for index in 0..torrent.piece_count() {
+        have.set(index).unwrap();
+    }
+    framed.send(Message::Bitfield(have)).await.unwrap();
+
+    let mut conn = Connection::default();
+    while let Some(msg) = framed.next().await {
+        let msg = msg.unwrap();
+        conn.apply_incoming(&msg).unwrap();
+        match msg {
+            Message::Interested => framed.send(conn.unchoke_peer()).await.unwrap(),
+            Message::Request {
+                index,
+                offset,
+                length,
+            } => {
+                msg.validate(DEFAULT_MAX_BLOCK_SIZE).unwrap();
+                let start = (index * PIECE_LEN + offset) as usize;
+                let data = torrent.data.slice(start..start + length as usize);
+                framed
+                    .send(Message::Block {
+                        index,
+                        offset,
+                        data,
+                    })
+                    .await
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn leech(mut stream: DuplexStream, torrent: &Torrent) -> Vec<Bytes> {
+    let our_id = *b"-LS0001-leecher00000";
+    let us = Handshake::new(
+        INFO_HASH,
+        our_id,
+        HandshakeFlags::FAST | HandshakeFlags::EXTENDED,
+    );
+    let them = us.clone().exchange(&mut stream).await.unwrap();
+    assert_eq!(them.info_hash(), &INFO_HASH);
+    assert!(!them.is_self_connection(&our_id));
+
+    let extensions = us.flags().negotiate(&them.flags());
+    assert_eq!(extensions, HandshakeFlags::FAST);
+    let mut framed = Framed::new(stream, PwpCodec::with_extensions(extensions));
+
+    let available = match framed.next().await.unwrap().unwrap() {
+        Message::Bitfield(b) => b,
+        other => panic!("expected a bitfield, got {}", other),
+    };
+    assert_eq!(available.count_ones(), u64::from(torrent.piece_count()));
+
+    let mut conn = Connection::default();
+    framed.send(conn.set_interested()).await.unwrap();
+    while !conn.can_request() {
+        let msg = framed.next().await.unwrap().unwrap();
+        conn.apply_incoming(&msg).unwrap();
+    }
+
+    let mut assembler =
+        PieceAssembler::new(PIECE_LEN, TOTAL_LEN).with_piece_hashes(torrent.hashes.clone());
+    let mut pieces = Vec::new();
+    for index in available.iter() {
+        let piece_len = assembler.piece_len(index).unwrap();
+        for request in Message::request_blocks(index, piece_len, DEFAULT_MAX_BLOCK_SIZE) {
+            if let Message::Request { offset, .. } = request {
+                conn.note_request_sent(index, offset, std::time::Instant::now());
+            }
+            framed.send(request).await.unwrap();
+        }
+
+        let piece = loop {
+            match framed.next().await.unwrap().unwrap() {
+                Message::Block {
+                    index,
+                    offset,
+                    data,
+                } => {
+                    assert!(conn
+                        .note_block_received(index, offset, std::time::Instant::now())
+                        .is_some());
+                    if let Some(piece) = assembler.add_block(index, offset, &data).unwrap() {
+                        break piece;
+                    }
+                }
+                other => panic!("expected a block, got {}", other),
+            }
+        };
+
+        pieces.push(piece);
+    }
+
+    assert!(conn.rtt().is_some());
+    pieces
+}
+
+#[tokio::test]
+async fn download_whole_torrent() {
+    let torrent = Torrent::generate();
+    let (seeder, leecher) = tokio::io::duplex(64 * 1024);
+
+    // The leecher drops its end of the stream once it's done, which lets the seeder finish.
+    let ((), pieces) = tokio::join!(seed(seeder, &torrent), leech(leecher, &torrent));
+
+    assert_eq!(pieces.len(), 3);
+    assert_eq!(pieces.concat(), torrent.data);
+}